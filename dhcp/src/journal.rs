@@ -0,0 +1,229 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::lease::{ClientId, LeaseState};
+
+// Records appended since last compaction before the journal is rewritten.
+const COMPACT_AFTER: usize = 1024;
+
+/*
+ * Append-only lease journal, one record per line:
+ *
+ *   bound 10.0.0.50 01aabbccddeeff 1760000000
+ *   declined 10.0.0.51 - 1760000000
+ *   free 10.0.0.50
+ *
+ * Later records for an address replace earlier ones. Expiry is wall clock
+ * time in seconds, so leases keep their remaining time across restarts.
+ * Every record is synced before the reply goes out, and a line cut short
+ * by a crash (no newline yet) is ignored when reading.
+ */
+pub struct LeaseJournal {
+    path: PathBuf,
+    file: File,
+    appended: usize
+}
+
+// Lease as stored on disk. Offers are not stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeaseRecord {
+    pub client: ClientId,
+    pub addr: Ipv4Addr,
+    pub state: LeaseState,
+    pub expires: SystemTime
+}
+
+enum Entry {
+    Set(LeaseRecord),
+    Free(Ipv4Addr)
+}
+
+impl LeaseJournal {
+    // Open journal for appending, creating it if needed. Returns leases it holds.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<(Self, Vec<LeaseRecord>)> {
+        let path = path.into();
+        let records = match load(&path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            records => records?
+        };
+
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+
+        // Drop record cut short by a crash, so the next one doesn't complete it.
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let len = data.iter().rposition(|byte| *byte == b'\n').map_or(0, |idx| idx + 1);
+        if len < data.len() {
+            file.set_len(len as u64)?;
+        }
+
+        Ok((LeaseJournal { path, file, appended: 0 }, records))
+    }
+
+    pub fn set(&mut self, record: &LeaseRecord) -> io::Result<()> {
+        self.append(&format_record(record))
+    }
+
+    pub fn free(&mut self, addr: Ipv4Addr) -> io::Result<()> {
+        self.append(&format!("free {}\n", addr))
+    }
+
+    // Journal grew enough to be worth rewriting.
+    pub fn needs_compaction(&self) -> bool {
+        self.appended >= COMPACT_AFTER
+    }
+
+    /*
+     * Replace the journal with one record per live lease. New file is
+     * written next to the old one and renamed over it, so a crash leaves
+     * one or the other.
+     */
+    pub fn compact<'a>(&mut self, leases: impl Iterator<Item = &'a LeaseRecord>) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            for record in leases {
+                file.write_all(format_record(record).as_bytes())?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.appended = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.appended += 1;
+        Ok(())
+    }
+}
+
+// Leases in the journal, expired ones included. Doesn't modify the file, so
+// it's safe to use while the server is running.
+pub fn load(path: &Path) -> io::Result<Vec<LeaseRecord>> {
+    let mut records: Vec<LeaseRecord> = Vec::new();
+    let mut data = String::new();
+    File::open(path)?.read_to_string(&mut data)?;
+
+    // Whatever follows the last newline is a record cut short by a crash.
+    let mut lines = data.split('\n').collect::<Vec<&str>>();
+    lines.pop();
+
+    for line in lines {
+        match parse_entry(line) {
+            Some(Entry::Set(record)) => {
+                records.retain(|other| other.addr != record.addr);
+                records.push(record);
+            },
+            Some(Entry::Free(addr)) => records.retain(|other| other.addr != addr),
+            // Garbage, kept out of '--leases' output.
+            None => eprintln!("Skipping lease journal line: '{}'", line)
+        }
+    }
+    Ok(records)
+}
+
+fn format_record(record: &LeaseRecord) -> String {
+    let state = match record.state {
+        LeaseState::Declined => "declined",
+        _ => "bound"
+    };
+    let client = if record.client.is_empty() {
+        "-".to_string()
+    } else {
+        record.client.iter().map(|byte| format!("{:02x}", byte)).collect()
+    };
+    let expires = record.expires.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    format!("{} {} {} {}\n", state, record.addr, client, expires)
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut parts = line.split(' ');
+    let kind = parts.next()?;
+    let addr = parts.next()?.parse::<Ipv4Addr>().ok()?;
+
+    let state = match kind {
+        "free" => return parts.next().map_or(Some(Entry::Free(addr)), |_| None),
+        "bound" => LeaseState::Bound,
+        "declined" => LeaseState::Declined,
+        _ => return None
+    };
+    let client = match parts.next()? {
+        "-" => Vec::new(),
+        hex if hex.len().is_multiple_of(2) => (0..hex.len())
+            .step_by(2)
+            .map(|idx| hex.get(idx..idx + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect::<Option<Vec<u8>>>()?,
+        _ => return None
+    };
+    let expires = UNIX_EPOCH + Duration::from_secs(parts.next()?.parse::<u64>().ok()?);
+
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Entry::Set(LeaseRecord { client, addr, state, expires }))
+}
+
+#[test]
+fn journal_test() {
+    let path = std::env::temp_dir().join(format!("dhcp-journal-{}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let expires = UNIX_EPOCH + Duration::from_secs(1760000000);
+    let record = |last: u8, state: LeaseState| LeaseRecord {
+        client: vec![1, 0xAB, last],
+        addr: Ipv4Addr::new(10, 0, 0, last),
+        state,
+        expires
+    };
+
+    let (mut journal, records) = LeaseJournal::open(&path).unwrap();
+    assert!(records.is_empty());
+    journal.set(&record(1, LeaseState::Bound)).unwrap();
+    journal.set(&record(2, LeaseState::Bound)).unwrap();
+    journal.free(Ipv4Addr::new(10, 0, 0, 1)).unwrap();
+    journal.set(&LeaseRecord { client: Vec::new(), ..record(3, LeaseState::Declined) }).unwrap();
+    journal.set(&LeaseRecord { expires: expires + Duration::from_secs(60), ..record(2, LeaseState::Bound) }).unwrap();
+
+    // Crash in the middle of a record.
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(b"bound 10.0.0.4 01ab").unwrap();
+    assert_eq!(load(&path).unwrap().len(), 2);
+
+    let records = load(&path).unwrap();
+    assert_eq!(records, vec![
+        LeaseRecord { client: Vec::new(), ..record(3, LeaseState::Declined) },
+        LeaseRecord { expires: expires + Duration::from_secs(60), ..record(2, LeaseState::Bound) }
+    ]);
+
+    // Cut record is dropped, next one starts on its own line.
+    let (mut journal, reopened) = LeaseJournal::open(&path).unwrap();
+    assert_eq!(reopened, records);
+    journal.set(&record(5, LeaseState::Bound)).unwrap();
+    assert_eq!(load(&path).unwrap().last(), Some(&record(5, LeaseState::Bound)));
+    assert!(!fs::read_to_string(&path).unwrap().contains("10.0.0.4"));
+
+    let records = load(&path).unwrap();
+    journal.compact(records.iter()).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+    assert_eq!(load(&path).unwrap(), records);
+
+    // Cut records can look valid: 'free 10.0.0.12' as 'free 10.0.0.1', expiry
+    // as some time in 1970. Neither may touch the lease that's there.
+    journal.set(&record(1, LeaseState::Bound)).unwrap();
+    let full = fs::read(&path).unwrap();
+    for cut in &["free 10.0.0.1", "bound 10.0.0.1 01ab01 17"] {
+        fs::write(&path, [&full[..], cut.as_bytes()].concat()).unwrap();
+        let records = load(&path).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records.last(), Some(&record(1, LeaseState::Bound)));
+    }
+
+    let _ = fs::remove_file(&path);
+}
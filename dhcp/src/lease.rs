@@ -0,0 +1,376 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{DHCPDgram, DHCPOption};
+use crate::journal::{LeaseJournal, LeaseRecord};
+
+// How long an OFFER reserves an address while waiting for REQUEST.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Client identifier. Either contents of option 61 or hardware address.
+pub type ClientId = Vec<u8>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LeaseState {
+    Offered,
+    Bound,
+    // Address reported by client as already in use.
+    Declined
+}
+
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub client: ClientId,
+    pub addr: Ipv4Addr,
+    pub state: LeaseState,
+    pub expires: Instant
+}
+
+pub struct LeasePool {
+    first: u32,
+    last: u32,
+    lease_time: Duration,
+    leases: HashMap<Ipv4Addr, Lease>,
+    // Fixed for particular hosts, never handed out dynamically.
+    reserved: HashSet<Ipv4Addr>,
+    // Bound and declined leases are written here to survive restarts.
+    journal: Option<LeaseJournal>
+}
+
+impl LeasePool {
+    pub fn new(first: Ipv4Addr, last: Ipv4Addr, lease_time: Duration) -> Self {
+        Self {
+            first: u32::from(first),
+            last: u32::from(last),
+            lease_time,
+            leases: HashMap::new(),
+            reserved: HashSet::new(),
+            journal: None
+        }
+    }
+
+    /*
+     * Keep leases in a journal file, restoring ones it already holds.
+     * Leases which expired meanwhile, fell out of the pool or ended up
+     * on a reserved address are dropped. Reserve addresses first.
+     */
+    pub fn persist(mut self, path: impl Into<PathBuf>) -> io::Result<Self> {
+        let (journal, records) = LeaseJournal::open(path)?;
+        let (now, wall_now) = (Instant::now(), SystemTime::now());
+
+        for record in records {
+            let remaining = match record.expires.duration_since(wall_now) {
+                Ok(remaining) => remaining,
+                Err(_) => continue
+            };
+            if self.contains(record.addr) && !self.reserved.contains(&record.addr) {
+                let lease = Lease { client: record.client, addr: record.addr, state: record.state, expires: now + remaining };
+                self.leases.insert(record.addr, lease);
+            }
+        }
+
+        self.journal = Some(journal);
+        self.compact()?;
+        Ok(self)
+    }
+
+    // Keep address out of dynamic allocation.
+    pub fn reserve(&mut self, addr: Ipv4Addr) {
+        self.reserved.insert(addr);
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let addr = u32::from(addr);
+        addr >= self.first && addr <= self.last
+    }
+
+    // Option 51.
+    pub fn lease_time(&self) -> Duration {
+        self.lease_time
+    }
+
+    // Option 58. T1 defaults to half of the lease.
+    pub fn renewal_time(&self) -> Duration {
+        self.lease_time / 2
+    }
+
+    // Option 59. T2 defaults to 7/8 of the lease.
+    pub fn rebinding_time(&self) -> Duration {
+        self.lease_time * 7 / 8
+    }
+
+    pub fn lease(&self, addr: Ipv4Addr) -> Option<&Lease> {
+        self.leases.get(&addr)
+    }
+
+    pub fn leases(&self) -> impl Iterator<Item = &Lease> {
+        self.leases.values()
+    }
+
+    // Pick address for DISCOVER. Previous lease of the client wins, then
+    // requested address, then first free one.
+    pub fn offer(&mut self, client: &[u8], requested: Option<Ipv4Addr>) -> Option<Ipv4Addr> {
+        self.expire(Instant::now());
+
+        let addr = self.find(client)
+            .or_else(|| requested.filter(|addr| self.is_free(*addr)))
+            .or_else(|| self.first_free())?;
+
+        // Bound lease is only extended by REQUEST.
+        if let Some(LeaseState::Bound) = self.leases.get(&addr).map(|lease| lease.state) {
+            return Some(addr);
+        }
+        self.insert(client, addr, LeaseState::Offered, OFFER_TIMEOUT);
+        Some(addr)
+    }
+
+    // Address offered to the client. Bound client is offered its lease again.
+    pub fn offered(&self, client: &[u8]) -> Option<Ipv4Addr> {
+        self.find(client)
+    }
+
+    // Bind address to the client. Returns None if address can't be given to the client.
+    pub fn request(&mut self, client: &[u8], addr: Ipv4Addr) -> Option<&Lease> {
+        self.expire(Instant::now());
+
+        let owned = self.leases.get(&addr)
+            .map(|lease| lease.client == client && lease.state != LeaseState::Declined)
+            .unwrap_or(false);
+        if !owned && !self.is_free(addr) {
+            return None;
+        }
+
+        // Client may hold only one address at a time.
+        if let Some(old) = self.find(client).filter(|old| *old != addr) {
+            self.remove(old);
+        }
+
+        let lease_time = self.lease_time;
+        Some(self.insert(client, addr, LeaseState::Bound, lease_time))
+    }
+
+    pub fn release(&mut self, client: &[u8], addr: Ipv4Addr) -> bool {
+        let owned = self.leases.get(&addr)
+            .map(|lease| lease.client == client)
+            .unwrap_or(false);
+        if owned {
+            self.remove(addr);
+        }
+        owned
+    }
+
+    // Client went with some other server.
+    pub fn cancel(&mut self, client: &[u8]) {
+        if let Some(addr) = self.find(client) {
+            self.remove(addr);
+        }
+    }
+
+    // Address is used by someone outside our control. Quarantine it for one lease period.
+    pub fn decline(&mut self, client: &[u8], addr: Ipv4Addr) -> bool {
+        let owned = self.leases.get(&addr)
+            .map(|lease| lease.client == client)
+            .unwrap_or(false);
+        if owned {
+            let lease_time = self.lease_time;
+            self.insert(&[], addr, LeaseState::Declined, lease_time);
+        }
+        owned
+    }
+
+    // Drop leases which expired before 'now'.
+    pub fn expire(&mut self, now: Instant) {
+        self.leases.retain(|_, lease| lease.expires > now);
+    }
+
+    fn insert(&mut self, client: &[u8], addr: Ipv4Addr, state: LeaseState, timeout: Duration) -> &Lease {
+        let lease = Lease {
+            client: client.to_vec(),
+            addr,
+            state,
+            expires: Instant::now() + timeout
+        };
+        // Offers are short lived, nothing to restore after restart.
+        if state != LeaseState::Offered {
+            let record = record(&lease);
+            self.log(|journal| journal.set(&record));
+        }
+        self.leases.insert(addr, lease);
+        &self.leases[&addr]
+    }
+
+    fn remove(&mut self, addr: Ipv4Addr) {
+        let removed = self.leases.remove(&addr);
+        if removed.is_some_and(|lease| lease.state != LeaseState::Offered) {
+            self.log(|journal| journal.free(addr));
+        }
+    }
+
+    // Failed write doesn't stop serving, the lease is just forgotten on restart.
+    fn log(&mut self, write: impl FnOnce(&mut LeaseJournal) -> io::Result<()>) {
+        let journal = match self.journal.as_mut() {
+            Some(journal) => journal,
+            None => return
+        };
+        if let Err(err) = write(journal) {
+            eprintln!("Unable to write lease journal: {}", err);
+        }
+        if journal.needs_compaction() {
+            if let Err(err) = self.compact() {
+                eprintln!("Unable to compact lease journal: {}", err);
+            }
+        }
+    }
+
+    // Rewrite journal with live leases only.
+    fn compact(&mut self) -> io::Result<()> {
+        let records = self.leases.values()
+            .filter(|lease| lease.state != LeaseState::Offered)
+            .map(record)
+            .collect::<Vec<LeaseRecord>>();
+        match self.journal.as_mut() {
+            Some(journal) => journal.compact(records.iter()),
+            None => Ok(())
+        }
+    }
+
+    fn find(&self, client: &[u8]) -> Option<Ipv4Addr> {
+        self.leases.values()
+            .filter(|lease| lease.client == client && lease.state != LeaseState::Declined)
+            .map(|lease| lease.addr)
+            .next()
+    }
+
+    fn is_free(&self, addr: Ipv4Addr) -> bool {
+        self.contains(addr) && !self.leases.contains_key(&addr) && !self.reserved.contains(&addr)
+    }
+
+    fn first_free(&self) -> Option<Ipv4Addr> {
+        (self.first..=self.last)
+            .map(Ipv4Addr::from)
+            .find(|addr| !self.leases.contains_key(addr) && !self.reserved.contains(addr))
+    }
+}
+
+// Journal keeps wall clock expiry, which means something after restart.
+fn record(lease: &Lease) -> LeaseRecord {
+    LeaseRecord {
+        client: lease.client.clone(),
+        addr: lease.addr,
+        state: lease.state,
+        expires: SystemTime::now() + lease.expires.saturating_duration_since(Instant::now())
+    }
+}
+
+// Identify client by option 61 if present, 'chaddr' otherwise.
+pub fn client_id(dhcp: &DHCPDgram) -> ClientId {
+    match dhcp.option(DHCPOption::CLIENT_ID) {
+        Some(DHCPOption::ClientId(id)) if !id.is_empty() => id.clone(),
+        _ => {
+            let hlen = (dhcp.body.hlen as usize).min(16);
+            dhcp.body.chaddr[..hlen].to_vec()
+        }
+    }
+}
+
+#[test]
+fn offer_request_test() {
+    let mut pool = LeasePool::new(Ipv4Addr::new(10, 0, 0, 10),
+                                  Ipv4Addr::new(10, 0, 0, 11),
+                                  Duration::from_secs(3600));
+
+    let a = pool.offer(b"a", None).unwrap();
+    assert_eq!(a, Ipv4Addr::new(10, 0, 0, 10));
+    // Same client gets the same offer.
+    assert_eq!(pool.offer(b"a", None), Some(a));
+    assert_eq!(pool.offered(b"a"), Some(a));
+
+    // Requested address honored if free, ignored if taken or out of range.
+    assert_eq!(pool.offer(b"b", Some(a)), Some(Ipv4Addr::new(10, 0, 0, 11)));
+    assert_eq!(pool.offer(b"c", Some(Ipv4Addr::new(10, 0, 0, 1))), None);
+
+    assert!(pool.request(b"b", a).is_none());
+    assert_eq!(pool.request(b"a", a).map(|lease| lease.state), Some(LeaseState::Bound));
+
+    assert!(pool.release(b"a", a));
+    assert!(pool.lease(a).is_none());
+    assert!(pool.request(b"c", a).is_some());
+}
+
+#[test]
+fn decline_expire_test() {
+    let mut pool = LeasePool::new(Ipv4Addr::new(10, 0, 0, 10),
+                                  Ipv4Addr::new(10, 0, 0, 10),
+                                  Duration::from_secs(3600));
+
+    let a = pool.offer(b"a", None).unwrap();
+    assert!(pool.decline(b"a", a));
+    assert_eq!(pool.offer(b"a", None), None);
+
+    pool.expire(Instant::now() + Duration::from_secs(3601));
+    assert_eq!(pool.offer(b"a", None), Some(a));
+}
+
+#[test]
+fn reserve_test() {
+    let mut pool = LeasePool::new(Ipv4Addr::new(10, 0, 0, 10),
+                                  Ipv4Addr::new(10, 0, 0, 11),
+                                  Duration::from_secs(3600));
+    pool.reserve(Ipv4Addr::new(10, 0, 0, 10));
+
+    // Reserved address is neither picked nor given on request.
+    assert_eq!(pool.offer(b"a", Some(Ipv4Addr::new(10, 0, 0, 10))), Some(Ipv4Addr::new(10, 0, 0, 11)));
+    assert_eq!(pool.offer(b"b", None), None);
+    assert!(pool.request(b"b", Ipv4Addr::new(10, 0, 0, 10)).is_none());
+}
+
+#[test]
+fn persist_test() {
+    use std::fs;
+
+    let path = std::env::temp_dir().join(format!("dhcp-leases-{}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mk_pool = || LeasePool::new(Ipv4Addr::new(10, 0, 0, 10),
+                                    Ipv4Addr::new(10, 0, 0, 13),
+                                    Duration::from_secs(3600));
+
+    let mut pool = mk_pool().persist(&path).unwrap();
+    let a = pool.offer(b"a", None).unwrap();
+    pool.request(b"a", a).unwrap();
+    let b = pool.offer(b"b", None).unwrap();
+    pool.request(b"b", b).unwrap();
+    let c = pool.offer(b"c", None).unwrap();
+    let d = pool.offer(b"d", None).unwrap();
+    pool.request(b"d", d).unwrap();
+    assert!(pool.decline(b"d", d));
+    assert!(pool.release(b"b", b));
+    drop(pool);
+
+    // Bound and declined leases come back, released and offered ones don't.
+    let mut pool = mk_pool().persist(&path).unwrap();
+    assert_eq!(pool.lease(a).map(|lease| (lease.client.clone(), lease.state)), Some((b"a".to_vec(), LeaseState::Bound)));
+    assert_eq!(pool.lease(d).map(|lease| lease.state), Some(LeaseState::Declined));
+    assert!(pool.lease(b).is_none());
+    assert!(pool.lease(c).is_none());
+    assert!(pool.lease(a).unwrap().expires > Instant::now() + Duration::from_secs(3500));
+
+    // Journal was compacted on startup.
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+    // DISCOVER from bound client neither extends the lease nor touches the journal.
+    let expires = pool.lease(a).unwrap().expires;
+    assert_eq!(pool.offer(b"a", None), Some(a));
+    assert_eq!(pool.offer(b"a", None), Some(a));
+    assert_eq!(pool.lease(a).map(|lease| (lease.state, lease.expires)), Some((LeaseState::Bound, expires)));
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+    // Address reserved meanwhile is not given back.
+    let mut reserved = mk_pool();
+    reserved.reserve(a);
+    assert!(reserved.persist(&path).unwrap().lease(a).is_none());
+
+    let _ = fs::remove_file(&path);
+}
@@ -1,20 +1,36 @@
-#![allow(safe_packed_borrows, unused_must_use)]
+#![allow(unused_must_use)]
 
 #[macro_use]
 extern crate derive_builder;
 #[macro_use]
 extern crate arrayref;
 
-use std::mem;
 use std::default::Default;
+use std::error::Error;
 use std::fmt::{Display, Formatter, Result};
 use std::net::Ipv4Addr;
 use std::borrow::Borrow;
 
 use phf::{Map, phf_map};
 
-#[repr(packed)]
-#[derive(Builder, Copy)]
+pub mod journal;
+pub mod lease;
+pub mod option;
+pub mod reservation;
+
+pub use option::{DHCPOption, MessageType};
+
+// Fixed part of the datagram, magic cookie included.
+pub const BODY_LEN: usize = 240;
+
+pub const MAGIC_COOKIE: u32 = 0x63825363;
+
+// Datagram every client must accept, IP and UDP headers included.
+pub const MIN_MESSAGE_SIZE: usize = 576;
+
+const IP_UDP_HEADERS: usize = 28;
+
+#[derive(Builder, Clone, Copy)]
 #[builder(default)]
 pub struct DHCPBody {
     pub op: u8,
@@ -34,65 +50,86 @@ pub struct DHCPBody {
     pub mcookie: u32
 }
 
-#[derive(Clone)]
-pub struct DHCPOption(pub u8, pub u8, pub Vec<u8>);
-
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct DHCPDgram {
     pub body: DHCPBody,
     pub options: Vec<DHCPOption>
 }
 
+#[derive(Debug, PartialEq)]
+pub enum DhcpParseError {
+    // Less bytes than fixed part of the datagram.
+    ShortPacket(usize),
+    BadMagicCookie(u32),
+    // Option code whose length or data runs past the end of the packet.
+    TruncatedOption(u8),
+    MissingEnd,
+    InvalidMessageType(Vec<u8>)
+}
+
 impl DHCPDgram {
-    pub fn swap_endianess(&self) -> Self {
-        Self {
-            body: self.body.swap_endianess(),
-            ..self.clone()
+    pub fn from_bytes(bytes: &[u8]) -> std::result::Result<Self, DhcpParseError> {
+        if bytes.len() < BODY_LEN {
+            return Err(DhcpParseError::ShortPacket(bytes.len()));
         }
-    }
 
-    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        // Not enough bytes.
-        if bytes.len() < mem::size_of::<DHCPBody>() {
-            return None;
+        let body = DHCPBody::from_bytes(array_ref![bytes, 0, BODY_LEN]);
+        if body.mcookie != MAGIC_COOKIE {
+            return Err(DhcpParseError::BadMagicCookie(body.mcookie));
         }
 
-        let main = &bytes[..mem::size_of::<DHCPBody>()];
-        let rest = &bytes[mem::size_of::<DHCPBody>()..];
+        let options = read_options(&bytes[BODY_LEN..])?;
 
-        let mut dhcp_buff = [0u8; mem::size_of::<DHCPBody>()];
-        dhcp_buff.copy_from_slice(&main[..]);
-
-        let dhcp: DHCPBody = unsafe { mem::transmute(dhcp_buff) };
-        let options: Vec<DHCPOption> = read_options(rest);
+        // Message type is the one option everything else depends on, so it must make sense.
+        if let Some(DHCPOption::Unknown(DHCPOption::MESSAGE_TYPE, data)) = options.iter()
+            .find(|option| option.code() == DHCPOption::MESSAGE_TYPE) {
+            return Err(DhcpParseError::InvalidMessageType(data.clone()));
+        }
 
-        Some(Self {
-            body: dhcp.clone(),
-            options: options
+        Ok(Self {
+            body,
+            options
         })
     }
 
     pub fn as_bytes(self) -> Vec<u8> {
-        let mut body_buff = [0u8; mem::size_of::<DHCPBody>()];
-        body_buff = unsafe { mem::transmute(self.body) };
-
-        let options_buff = self.options.into_iter()
+        let options_buff = self.options.iter()
             .fold(Vec::<u8>::new(), |mut acc, x| {
-                let mut v = x.2.to_vec();
-                v.insert(0, x.1);
-                v.insert(0, x.0);
-                acc.extend(&v);
+                acc.extend(x.encode());
                 acc
             });
 
-        vec![body_buff.to_vec(), options_buff].concat()
+        [self.body.as_bytes(), options_buff].concat()
     }
 
-    pub fn option(&self, id: u8) -> Option<&[u8]> {
+    pub fn option(&self, code: u8) -> Option<&DHCPOption> {
         self.options.iter()
-            .filter(|option| option.0 == id)
-            .map(|option| &option.2[..])
-            .next()
+            .find(|option| option.code() == code)
+    }
+
+    pub fn message_type(&self) -> Option<MessageType> {
+        match self.option(DHCPOption::MESSAGE_TYPE) {
+            Some(DHCPOption::MessageType(msg_type)) => Some(*msg_type),
+            _ => None
+        }
+    }
+
+    // Option codes client asked for in option 55, most wanted first.
+    pub fn requested_options(&self) -> &[u8] {
+        match self.option(DHCPOption::PARAMETER_REQUEST_LIST) {
+            Some(DHCPOption::ParameterRequestList(codes)) => codes,
+            _ => &[]
+        }
+    }
+
+    // Longest reply client accepts, without IP and UDP headers. Option 57
+    // can only raise the limit.
+    pub fn max_message_size(&self) -> usize {
+        let size = match self.option(DHCPOption::MAX_MESSAGE_SIZE) {
+            Some(DHCPOption::MaxMessageSize(size)) => (*size as usize).max(MIN_MESSAGE_SIZE),
+            _ => MIN_MESSAGE_SIZE
+        };
+        size - IP_UDP_HEADERS
     }
 }
 
@@ -103,8 +140,8 @@ pub struct DHCPDgramBuilder {
 }
 
 impl DHCPDgramBuilder {
-    pub fn option(mut self, code: u8, data: &[u8]) -> Self {
-        self.options.push(DHCPOption(code, data.len() as u8, data.to_vec()));
+    pub fn option(mut self, option: DHCPOption) -> Self {
+        self.options.push(option);
         self
     }
 
@@ -114,7 +151,7 @@ impl DHCPDgramBuilder {
     }
 
     pub fn end(self) -> Self {
-        self.option(0xFF, &[])
+        self.option(DHCPOption::End)
     }
 
     pub fn build(self) -> Option<DHCPDgram> {
@@ -129,76 +166,98 @@ impl DHCPDgramBuilder {
 }
 
 impl DHCPBody {
-    fn swap_endianess(&self) -> Self {
+    // All multi-byte fields are in network byte order.
+    fn from_bytes(bytes: &[u8; BODY_LEN]) -> Self {
+        let (op, htype, hlen, hops, xid, secs, flags,
+             ciaddr, yiaddr, siaddr, giaddr,
+             chaddr, sname, filename, mcookie) =
+            array_refs![bytes, 1, 1, 1, 1, 4, 2, 2, 4, 4, 4, 4, 16, 64, 128, 4];
+
         Self {
-            xid: self.xid.swap_bytes(),
-            secs: self.secs.swap_bytes(),
-            flags: self.flags.swap_bytes(),
-            mcookie: self.mcookie.swap_bytes(),
-            ..self.clone()
+            op: op[0],
+            htype: htype[0],
+            hlen: hlen[0],
+            hops: hops[0],
+            xid: u32::from_be_bytes(*xid),
+            secs: u16::from_be_bytes(*secs),
+            flags: u16::from_be_bytes(*flags),
+            ciaddr: *ciaddr,
+            yiaddr: *yiaddr,
+            siaddr: *siaddr,
+            giaddr: *giaddr,
+            chaddr: *chaddr,
+            sname: *sname,
+            filename: *filename,
+            mcookie: u32::from_be_bytes(*mcookie)
         }
     }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        [
+            &[self.op, self.htype, self.hlen, self.hops][..],
+            &self.xid.to_be_bytes(),
+            &self.secs.to_be_bytes(),
+            &self.flags.to_be_bytes(),
+            &self.ciaddr,
+            &self.yiaddr,
+            &self.siaddr,
+            &self.giaddr,
+            &self.chaddr,
+            &self.sname,
+            &self.filename,
+            &self.mcookie.to_be_bytes()
+        ].concat()
+    }
 }
 
-// Parse byte array with options.
-fn read_options(data: &[u8]) -> Vec<DHCPOption> {
+// Parse byte array with options. Everything after END is padding.
+// Options split into several with the same code (RFC 3396) are joined.
+fn read_options(data: &[u8]) -> std::result::Result<Vec<DHCPOption>, DhcpParseError> {
     let mut idx = 0;
-    let mut options = Vec::with_capacity(256);
+    let mut raw: Vec<(u8, Vec<u8>)> = Vec::new();
 
     loop {
-        let option = match (data.get(idx), data.get(idx+1)) {
+        match (data.get(idx), data.get(idx+1)) {
+            // Ran out of data before END
+            (None, _) => return Err(DhcpParseError::MissingEnd),
             // Padding byte
             (Some(0x00), _) => {
                 idx += 1;
-                Some(DHCPOption(0x00, 0, vec![]))
+                raw.push((DHCPOption::PAD, Vec::new()));
             },
+            (Some(0xFF), _) => break,
+            // Option without length byte
+            (Some(code), None) => return Err(DhcpParseError::TruncatedOption(*code)),
             // Option byte
             (Some(code), Some(length)) => {
-                let length_us = *length as usize;
-                if idx+length_us+2 > data.len() {
-                    None
-                } else {
-                    let option = DHCPOption(*code, *length,
-                                            data[idx+2..idx+length_us+2].to_vec());
-                    idx += length_us+2;
-                    Some(option)
+                let end = idx + *length as usize + 2;
+                if end > data.len() {
+                    return Err(DhcpParseError::TruncatedOption(*code));
                 }
-            },
-            // Propbably end of the data
-            _ => None
-        };
-
-        if option.is_none() {
-            break;
+                match raw.iter_mut().find(|(other, _)| other == code) {
+                    Some((_, joined)) => joined.extend_from_slice(&data[idx+2..end]),
+                    None => raw.push((*code, data[idx+2..end].to_vec()))
+                }
+                idx = end;
+            }
         }
-        options.push(option.unwrap());
     }
 
-    options
+    Ok(raw.iter()
+       .map(|(code, data)| DHCPOption::decode(*code, data))
+       .chain(std::iter::once(DHCPOption::End))
+       .collect())
 }
 
-impl Default for DHCPDgram {
+impl Default for DHCPBody {
     fn default() -> Self {
         Self {
-            options: Vec::new(),
-            body: Default::default()
+            mcookie: MAGIC_COOKIE,
+            ..Self::from_bytes(&[0u8; BODY_LEN])
         }
     }
 }
 
-impl Default for DHCPBody {
-    fn default() -> Self {
-        let bytes = [0u8; mem::size_of::<DHCPBody>()];
-        let mut body: DHCPBody = unsafe {
-            mem::transmute(bytes)
-        };
-        body.mcookie = 0x63825363;
-        body
-    }
-}
-
-impl Clone for DHCPBody { fn clone(&self) -> Self { *self } }
-
 static DHCP_OPERATION: Map<u8, &'static str> = phf_map! {
     0x01u8 => "BOOT REQUEST",
     0x02u8 => "BOOT REPLY"
@@ -220,10 +279,12 @@ static DHCP_OPTION_NAME: Map<u8, &'static str> = phf_map! {
     3u8 => "Router",
     6u8 => "Domain Name Server",
 
+    12u8 => "Host Name",
     15u8 => "Domain Name",
 
     43u8 => "Vendor-Specific Information (PXEClient)",
 
+    50u8 => "Requested IP Address",
     51u8 => "IP Address Lease Time",
     52u8 => "Overload 'sname' or 'file'",
     53u8 => "DHCP Message Type",
@@ -235,10 +296,16 @@ static DHCP_OPTION_NAME: Map<u8, &'static str> = phf_map! {
     60u8 => "Vendor class Identifier",
     61u8 => "Client Identifier",
 
+    66u8 => "TFTP Server Name",
+    67u8 => "Bootfile Name",
+    77u8 => "User Class",
+
     93u8 => "Client System Architecture",
     94u8 => "Client Network Device Interface",
     97u8 => "UUID/GUID-based Client Identifier",
 
+    175u8 => "iPXE Encapsulated Options",
+
     255u8 => "END"
 };
 
@@ -248,8 +315,8 @@ impl Display for DHCPDgram {
         writeln!(f, "OPTIONS:");
 
         for option in &self.options {
-            if option.0 == 0x00 {
-                break;
+            if *option == DHCPOption::Pad {
+                continue;
             }
             writeln!(f, "---");
             write!(f, "{}", option);
@@ -276,22 +343,42 @@ impl Display for DHCPBody {
 impl Display for DHCPOption {
     fn fmt(&self, f: &mut Formatter) -> Result {
         let name = DHCP_OPTION_NAME
-            .get(&self.0)
+            .get(&self.code())
             .unwrap_or(&"Unknown");
+        let data = self.data();
 
-        writeln!(f, "OPTION {} - '{}', LENGTH: {}", self.0, name, self.1);
-        match self.0 {
-            53 => {
+        writeln!(f, "OPTION {} - '{}', LENGTH: {}", self.code(), name, data.len());
+        match self {
+            DHCPOption::MessageType(msg_type) => {
                 let msg_type_name = DHCP_MESSAGE_TYPE
-                    .get(&self.2[0])
+                    .get(&(*msg_type as u8))
                     .unwrap_or(&"Unknown");
                 writeln!(f, "{}", msg_type_name)
             },
-            _ => writeln!(f, "DATA: {:?}", self.2)
+            _ => writeln!(f, "DATA: {:?}", data)
         }
     }
 }
 
+impl Display for DhcpParseError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            DhcpParseError::ShortPacket(len) =>
+                write!(f, "Packet too short: {} bytes, at least {} expected.", len, BODY_LEN),
+            DhcpParseError::BadMagicCookie(cookie) =>
+                write!(f, "Bad magic cookie: 0x{:08x}.", cookie),
+            DhcpParseError::TruncatedOption(code) =>
+                write!(f, "Option {} truncated.", code),
+            DhcpParseError::MissingEnd =>
+                write!(f, "Options not terminated with END."),
+            DhcpParseError::InvalidMessageType(data) =>
+                write!(f, "Invalid message type: {:?}.", data)
+        }
+    }
+}
+
+impl Error for DhcpParseError {}
+
 fn ipv4_str(octets: impl Borrow<[u8; 4]>) -> String {
     let octets = octets.borrow();
     Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).to_string()
@@ -303,3 +390,77 @@ fn mac_str(octets: impl Borrow<[u8; 6]>) -> String {
             octets[0], octets[1], octets[2],
             octets[3], octets[4], octets[5])
 }
+
+#[test]
+fn parse_roundtrip_test() {
+    let body = DHCPBody { op: 1, xid: 0xdeadbeef, secs: 4, flags: 0x8000, ..Default::default() };
+    let bytes = DHCPDgramBuilder::default()
+        .body(body)
+        .option(DHCPOption::MessageType(MessageType::Discover))
+        .option(DHCPOption::Pad)
+        .option(DHCPOption::ClientArch(vec![7]))
+        .end()
+        .build()
+        .unwrap()
+        .as_bytes();
+
+    // Network byte order on the wire.
+    assert_eq!(&bytes[4..12], &[0xde, 0xad, 0xbe, 0xef, 0x00, 0x04, 0x80, 0x00]);
+    assert_eq!(&bytes[236..240], &[0x63, 0x82, 0x53, 0x63]);
+
+    let dhcp = DHCPDgram::from_bytes(&[&bytes[..], &[0, 0, 0]].concat()).unwrap();
+    assert_eq!(dhcp.body.xid, 0xdeadbeef);
+    assert_eq!(dhcp.body.flags, 0x8000);
+    assert_eq!(dhcp.message_type(), Some(MessageType::Discover));
+    assert_eq!(dhcp.option(DHCPOption::CLIENT_ARCH), Some(&DHCPOption::ClientArch(vec![7])));
+    assert!(dhcp.requested_options().is_empty());
+    assert_eq!(dhcp.max_message_size(), 548);
+    assert_eq!(dhcp.clone().as_bytes(), bytes);
+
+    let mut dhcp = dhcp;
+    dhcp.options.insert(0, DHCPOption::ParameterRequestList(vec![67, 43, 1]));
+    dhcp.options.insert(0, DHCPOption::MaxMessageSize(1500));
+    assert_eq!(dhcp.requested_options(), &[67, 43, 1]);
+    assert_eq!(dhcp.max_message_size(), 1472);
+    dhcp.options[0] = DHCPOption::MaxMessageSize(300);
+    assert_eq!(dhcp.max_message_size(), 548);
+}
+
+#[test]
+fn long_option_test() {
+    let pxe = (0..300).map(|idx| idx as u8).collect::<Vec<u8>>();
+    let bytes = DHCPDgramBuilder::default()
+        .body(DHCPBody::default())
+        .option(DHCPOption::MessageType(MessageType::Offer))
+        .option(DHCPOption::VendorSpecific(pxe.clone()))
+        .end()
+        .build()
+        .unwrap()
+        .as_bytes();
+
+    // Split into 255 and 45 bytes on the wire, one option once parsed.
+    assert_eq!(&bytes[243..245], &[43, 255]);
+    assert_eq!(&bytes[500..502], &[43, 45]);
+    let dhcp = DHCPDgram::from_bytes(&bytes).unwrap();
+    assert_eq!(dhcp.options.len(), 3);
+    assert_eq!(dhcp.option(DHCPOption::VENDOR_SPECIFIC), Some(&DHCPOption::VendorSpecific(pxe)));
+    assert_eq!(dhcp.as_bytes(), bytes);
+}
+
+#[test]
+fn parse_error_test() {
+    let body = DHCPBody::default().as_bytes();
+    let parse = |options: &[u8]| DHCPDgram::from_bytes(&[&body[..], options].concat()).err();
+
+    assert_eq!(DHCPDgram::from_bytes(&body[..100]).err(), Some(DhcpParseError::ShortPacket(100)));
+    assert_eq!(parse(&[53, 1, 1]), Some(DhcpParseError::MissingEnd));
+    assert_eq!(parse(&[53, 1, 1, 60]), Some(DhcpParseError::TruncatedOption(60)));
+    assert_eq!(parse(&[53, 1, 1, 60, 9, 1, 2, 255]), Some(DhcpParseError::TruncatedOption(60)));
+    assert_eq!(parse(&[53, 1, 42, 255]), Some(DhcpParseError::InvalidMessageType(vec![42])));
+    assert_eq!(parse(&[53, 1, 1, 255]), None);
+
+    let mut bad_cookie = body.clone();
+    bad_cookie[239] = 0;
+    bad_cookie.push(255);
+    assert_eq!(DHCPDgram::from_bytes(&bad_cookie).err(), Some(DhcpParseError::BadMagicCookie(0x63825300)));
+}
@@ -0,0 +1,297 @@
+use std::convert::TryInto;
+use std::net::Ipv4Addr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8
+}
+
+impl MessageType {
+    pub fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(MessageType::Discover),
+            2 => Some(MessageType::Offer),
+            3 => Some(MessageType::Request),
+            4 => Some(MessageType::Decline),
+            5 => Some(MessageType::Ack),
+            6 => Some(MessageType::Nak),
+            7 => Some(MessageType::Release),
+            8 => Some(MessageType::Inform),
+            _ => None
+        }
+    }
+}
+
+/*
+ * Well-known DHCP options. Anything we don't know, or which doesn't fit
+ * the expected layout, lands in 'Unknown' so decoding is always lossless.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum DHCPOption {
+    Pad,
+    SubnetMask(Ipv4Addr),
+    Router(Vec<Ipv4Addr>),
+    DomainNameServer(Vec<Ipv4Addr>),
+    HostName(String),
+    DomainName(String),
+    VendorSpecific(Vec<u8>),
+    RequestedIp(Ipv4Addr),
+    LeaseTime(u32),
+    Overload(u8),
+    MessageType(MessageType),
+    ServerId(Ipv4Addr),
+    ParameterRequestList(Vec<u8>),
+    MaxMessageSize(u16),
+    RenewalTime(u32),
+    RebindingTime(u32),
+    VendorClass(Vec<u8>),
+    ClientId(Vec<u8>),
+    TFTPServerName(String),
+    BootfileName(String),
+    UserClass(Vec<u8>),
+    ClientArch(Vec<u16>),
+    // Interface type, major and minor UNDI version.
+    ClientNdi(u8, u8, u8),
+    // Identifier type and UUID itself.
+    ClientUuid(u8, [u8; 16]),
+    // iPXE encapsulated options.
+    IpxeEncap(Vec<u8>),
+    End,
+    Unknown(u8, Vec<u8>)
+}
+
+impl DHCPOption {
+    pub const PAD: u8 = 0;
+    pub const SUBNET_MASK: u8 = 1;
+    pub const ROUTER: u8 = 3;
+    pub const DOMAIN_NAME_SERVER: u8 = 6;
+    pub const HOST_NAME: u8 = 12;
+    pub const DOMAIN_NAME: u8 = 15;
+    pub const VENDOR_SPECIFIC: u8 = 43;
+    pub const REQUESTED_IP: u8 = 50;
+    pub const LEASE_TIME: u8 = 51;
+    pub const OVERLOAD: u8 = 52;
+    pub const MESSAGE_TYPE: u8 = 53;
+    pub const SERVER_ID: u8 = 54;
+    pub const PARAMETER_REQUEST_LIST: u8 = 55;
+    pub const MAX_MESSAGE_SIZE: u8 = 57;
+    pub const RENEWAL_TIME: u8 = 58;
+    pub const REBINDING_TIME: u8 = 59;
+    pub const VENDOR_CLASS: u8 = 60;
+    pub const CLIENT_ID: u8 = 61;
+    pub const TFTP_SERVER_NAME: u8 = 66;
+    pub const BOOTFILE_NAME: u8 = 67;
+    pub const USER_CLASS: u8 = 77;
+    pub const CLIENT_ARCH: u8 = 93;
+    pub const CLIENT_NDI: u8 = 94;
+    pub const CLIENT_UUID: u8 = 97;
+    pub const IPXE_ENCAP: u8 = 175;
+    pub const END: u8 = 255;
+
+    pub fn code(&self) -> u8 {
+        match self {
+            DHCPOption::Pad => Self::PAD,
+            DHCPOption::SubnetMask(_) => Self::SUBNET_MASK,
+            DHCPOption::Router(_) => Self::ROUTER,
+            DHCPOption::DomainNameServer(_) => Self::DOMAIN_NAME_SERVER,
+            DHCPOption::HostName(_) => Self::HOST_NAME,
+            DHCPOption::DomainName(_) => Self::DOMAIN_NAME,
+            DHCPOption::VendorSpecific(_) => Self::VENDOR_SPECIFIC,
+            DHCPOption::RequestedIp(_) => Self::REQUESTED_IP,
+            DHCPOption::LeaseTime(_) => Self::LEASE_TIME,
+            DHCPOption::Overload(_) => Self::OVERLOAD,
+            DHCPOption::MessageType(_) => Self::MESSAGE_TYPE,
+            DHCPOption::ServerId(_) => Self::SERVER_ID,
+            DHCPOption::ParameterRequestList(_) => Self::PARAMETER_REQUEST_LIST,
+            DHCPOption::MaxMessageSize(_) => Self::MAX_MESSAGE_SIZE,
+            DHCPOption::RenewalTime(_) => Self::RENEWAL_TIME,
+            DHCPOption::RebindingTime(_) => Self::REBINDING_TIME,
+            DHCPOption::VendorClass(_) => Self::VENDOR_CLASS,
+            DHCPOption::ClientId(_) => Self::CLIENT_ID,
+            DHCPOption::TFTPServerName(_) => Self::TFTP_SERVER_NAME,
+            DHCPOption::BootfileName(_) => Self::BOOTFILE_NAME,
+            DHCPOption::UserClass(_) => Self::USER_CLASS,
+            DHCPOption::ClientArch(_) => Self::CLIENT_ARCH,
+            DHCPOption::ClientNdi(..) => Self::CLIENT_NDI,
+            DHCPOption::ClientUuid(..) => Self::CLIENT_UUID,
+            DHCPOption::IpxeEncap(_) => Self::IPXE_ENCAP,
+            DHCPOption::End => Self::END,
+            DHCPOption::Unknown(code, _) => *code
+        }
+    }
+
+    // Option payload, without code and length.
+    pub fn data(&self) -> Vec<u8> {
+        let addrs = |addrs: &[Ipv4Addr]| {
+            addrs.iter()
+                .flat_map(|addr| addr.octets().to_vec())
+                .collect::<Vec<u8>>()
+        };
+
+        match self {
+            DHCPOption::Pad | DHCPOption::End => vec![],
+            DHCPOption::SubnetMask(addr)
+                | DHCPOption::RequestedIp(addr)
+                | DHCPOption::ServerId(addr) => addr.octets().to_vec(),
+            DHCPOption::Router(list)
+                | DHCPOption::DomainNameServer(list) => addrs(list),
+            DHCPOption::HostName(string)
+                | DHCPOption::DomainName(string)
+                | DHCPOption::TFTPServerName(string)
+                | DHCPOption::BootfileName(string) => string.as_bytes().to_vec(),
+            DHCPOption::VendorSpecific(bytes)
+                | DHCPOption::ParameterRequestList(bytes)
+                | DHCPOption::VendorClass(bytes)
+                | DHCPOption::ClientId(bytes)
+                | DHCPOption::UserClass(bytes)
+                | DHCPOption::IpxeEncap(bytes)
+                | DHCPOption::Unknown(_, bytes) => bytes.clone(),
+            DHCPOption::LeaseTime(secs)
+                | DHCPOption::RenewalTime(secs)
+                | DHCPOption::RebindingTime(secs) => secs.to_be_bytes().to_vec(),
+            DHCPOption::Overload(byte) => vec![*byte],
+            DHCPOption::MessageType(msg_type) => vec![*msg_type as u8],
+            DHCPOption::MaxMessageSize(size) => size.to_be_bytes().to_vec(),
+            DHCPOption::ClientArch(archs) => archs.iter()
+                .flat_map(|arch| arch.to_be_bytes().to_vec())
+                .collect(),
+            DHCPOption::ClientNdi(kind, major, minor) => vec![*kind, *major, *minor],
+            DHCPOption::ClientUuid(kind, uuid) => [&[*kind][..], &uuid[..]].concat()
+        }
+    }
+
+    // Wire format. Payloads longer than 255 bytes are split into several
+    // options with the same code, as described in RFC 3396.
+    pub fn encode(&self) -> Vec<u8> {
+        let code = self.code();
+        if code == Self::PAD || code == Self::END {
+            return vec![code];
+        }
+
+        let data = self.data();
+        if data.is_empty() {
+            return vec![code, 0];
+        }
+
+        data.chunks(255)
+            .flat_map(|chunk| [&[code, chunk.len() as u8][..], chunk].concat())
+            .collect()
+    }
+
+    // Interpret option payload. Falls back to 'Unknown' when payload is malformed.
+    pub fn decode(code: u8, data: &[u8]) -> Self {
+        Self::decode_known(code, data)
+            .unwrap_or_else(|| DHCPOption::Unknown(code, data.to_vec()))
+    }
+
+    fn decode_known(code: u8, data: &[u8]) -> Option<Self> {
+        let addr = || -> Option<Ipv4Addr> {
+            let octets: [u8; 4] = data.try_into().ok()?;
+            Some(Ipv4Addr::from(octets))
+        };
+        let addrs = || -> Option<Vec<Ipv4Addr>> {
+            if data.is_empty() || !data.len().is_multiple_of(4) {
+                return None;
+            }
+            Some(data.chunks(4)
+                 .map(|chunk| Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]))
+                 .collect())
+        };
+        let secs = || -> Option<u32> {
+            Some(u32::from_be_bytes(data.try_into().ok()?))
+        };
+        let string = || -> Option<String> {
+            String::from_utf8(data.to_vec()).ok()
+        };
+
+        let option = match code {
+            Self::PAD if data.is_empty() => DHCPOption::Pad,
+            Self::END if data.is_empty() => DHCPOption::End,
+            Self::SUBNET_MASK => DHCPOption::SubnetMask(addr()?),
+            Self::ROUTER => DHCPOption::Router(addrs()?),
+            Self::DOMAIN_NAME_SERVER => DHCPOption::DomainNameServer(addrs()?),
+            Self::HOST_NAME => DHCPOption::HostName(string()?),
+            Self::DOMAIN_NAME => DHCPOption::DomainName(string()?),
+            Self::VENDOR_SPECIFIC => DHCPOption::VendorSpecific(data.to_vec()),
+            Self::REQUESTED_IP => DHCPOption::RequestedIp(addr()?),
+            Self::LEASE_TIME => DHCPOption::LeaseTime(secs()?),
+            Self::OVERLOAD => match data {
+                &[byte] => DHCPOption::Overload(byte),
+                _ => return None
+            },
+            Self::MESSAGE_TYPE => match data {
+                &[byte] => DHCPOption::MessageType(MessageType::from_u8(byte)?),
+                _ => return None
+            },
+            Self::SERVER_ID => DHCPOption::ServerId(addr()?),
+            Self::PARAMETER_REQUEST_LIST => DHCPOption::ParameterRequestList(data.to_vec()),
+            Self::MAX_MESSAGE_SIZE => DHCPOption::MaxMessageSize(u16::from_be_bytes(data.try_into().ok()?)),
+            Self::RENEWAL_TIME => DHCPOption::RenewalTime(secs()?),
+            Self::REBINDING_TIME => DHCPOption::RebindingTime(secs()?),
+            Self::VENDOR_CLASS => DHCPOption::VendorClass(data.to_vec()),
+            Self::CLIENT_ID => DHCPOption::ClientId(data.to_vec()),
+            Self::TFTP_SERVER_NAME => DHCPOption::TFTPServerName(string()?),
+            Self::BOOTFILE_NAME => DHCPOption::BootfileName(string()?),
+            Self::USER_CLASS => DHCPOption::UserClass(data.to_vec()),
+            Self::CLIENT_ARCH if !data.is_empty() && data.len().is_multiple_of(2) => DHCPOption::ClientArch(
+                data.chunks(2)
+                    .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+                    .collect()
+            ),
+            Self::CLIENT_NDI => match data {
+                &[kind, major, minor] => DHCPOption::ClientNdi(kind, major, minor),
+                _ => return None
+            },
+            Self::IPXE_ENCAP => DHCPOption::IpxeEncap(data.to_vec()),
+            Self::CLIENT_UUID if data.len() == 17 => DHCPOption::ClientUuid(data[0], *array_ref![data, 1, 16]),
+            _ => return None
+        };
+        Some(option)
+    }
+}
+
+#[test]
+fn option_roundtrip_test() {
+    let options = vec![
+        DHCPOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+        DHCPOption::Router(vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]),
+        DHCPOption::LeaseTime(86400),
+        DHCPOption::MessageType(MessageType::Ack),
+        DHCPOption::ParameterRequestList(vec![1, 3, 6, 43]),
+        DHCPOption::MaxMessageSize(1500),
+        DHCPOption::VendorClass(b"PXEClient:Arch:00007".to_vec()),
+        DHCPOption::ClientArch(vec![7]),
+        DHCPOption::ClientNdi(1, 3, 16),
+        DHCPOption::ClientUuid(0, [0xAB; 16]),
+        DHCPOption::IpxeEncap(vec![1, 1, 1]),
+        DHCPOption::Unknown(224, vec![1, 2, 3])
+    ];
+
+    for option in options {
+        let bytes = option.encode();
+        assert_eq!(bytes[0], option.code());
+        assert_eq!(bytes[1] as usize, bytes.len() - 2);
+        assert_eq!(DHCPOption::decode(bytes[0], &bytes[2..]), option);
+    }
+}
+
+#[test]
+fn option_fallback_test() {
+    // Wrong length or value keeps raw bytes.
+    assert_eq!(DHCPOption::decode(1, &[255, 255, 0]), DHCPOption::Unknown(1, vec![255, 255, 0]));
+    assert_eq!(DHCPOption::decode(53, &[42]), DHCPOption::Unknown(53, vec![42]));
+    assert_eq!(DHCPOption::decode(93, &[0, 0]), DHCPOption::ClientArch(vec![0]));
+
+    assert_eq!(DHCPOption::End.encode(), vec![255]);
+    let long = DHCPOption::VendorSpecific(vec![0; 300]).encode();
+    assert_eq!(long.len(), 300 + 4);
+    assert_eq!(&long[..2], &[43, 255]);
+    assert_eq!(&long[257..259], &[43, 45]);
+}
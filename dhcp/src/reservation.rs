@@ -0,0 +1,88 @@
+use std::net::Ipv4Addr;
+
+use crate::{DHCPDgram, DHCPOption};
+
+/*
+ * Parameters fixed for one particular machine. It's recognized by any of
+ * the keys given: hardware address from 'chaddr', client identifier from
+ * option 61 or machine UUID from option 97, bytes as the client sends them.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Reservation {
+    pub mac: Option<[u8; 6]>,
+    pub client_id: Option<Vec<u8>>,
+    pub uuid: Option<[u8; 16]>,
+    // Address given instead of one from the pool.
+    pub addr: Option<Ipv4Addr>,
+    pub hostname: Option<String>,
+    pub bootfile: Option<String>
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Reservations {
+    hosts: Vec<Reservation>
+}
+
+impl Reservations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn host(mut self, host: Reservation) -> Self {
+        self.hosts.push(host);
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Reservation> {
+        self.hosts.iter()
+    }
+
+    // Reservation of the client. Client identifier is the most specific key, then UUID, then MAC.
+    pub fn find(&self, dhcp: &DHCPDgram) -> Option<&Reservation> {
+        let client_id = match dhcp.option(DHCPOption::CLIENT_ID) {
+            Some(DHCPOption::ClientId(id)) if !id.is_empty() => Some(id),
+            _ => None
+        };
+        let uuid = match dhcp.option(DHCPOption::CLIENT_UUID) {
+            Some(DHCPOption::ClientUuid(_, uuid)) => Some(uuid),
+            _ => None
+        };
+        // Only Ethernet addresses are reserved.
+        let mac = Some(array_ref![dhcp.body.chaddr, 0, 6])
+            .filter(|_| dhcp.body.hlen == 6);
+
+        client_id.and_then(|id| self.hosts.iter().find(|host| host.client_id.as_ref() == Some(id)))
+            .or_else(|| uuid.and_then(|uuid| self.hosts.iter().find(|host| host.uuid.as_ref() == Some(uuid))))
+            .or_else(|| mac.and_then(|mac| self.hosts.iter().find(|host| host.mac.as_ref() == Some(mac))))
+    }
+}
+
+#[test]
+fn reservation_test() {
+    use crate::{DHCPBody, DHCPDgramBuilder};
+
+    let mac = [0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD];
+    let hosts = Reservations::new()
+        .host(Reservation { mac: Some(mac), addr: Some(Ipv4Addr::new(10, 0, 0, 10)), ..Default::default() })
+        .host(Reservation { client_id: Some(b"\x00node2".to_vec()), addr: Some(Ipv4Addr::new(10, 0, 0, 11)), ..Default::default() })
+        .host(Reservation { uuid: Some([7; 16]), bootfile: Some("rescue.0".to_string()), ..Default::default() });
+
+    let mk_dgram = |options: Vec<DHCPOption>| {
+        let mut body = DHCPBody { hlen: 6, ..Default::default() };
+        body.chaddr[..6].copy_from_slice(&mac);
+        options.into_iter()
+            .fold(DHCPDgramBuilder::default().body(body), |builder, option| builder.option(option))
+            .end()
+            .build()
+            .unwrap()
+    };
+    let addr = |dhcp: DHCPDgram| hosts.find(&dhcp).and_then(|host| host.addr);
+
+    assert_eq!(addr(mk_dgram(vec![])), Some(Ipv4Addr::new(10, 0, 0, 10)));
+    // More specific keys win over hardware address.
+    assert_eq!(addr(mk_dgram(vec![DHCPOption::ClientId(b"\x00node2".to_vec())])), Some(Ipv4Addr::new(10, 0, 0, 11)));
+    assert_eq!(hosts.find(&mk_dgram(vec![DHCPOption::ClientUuid(0, [7; 16])])).and_then(|host| host.bootfile.as_deref()),
+               Some("rescue.0"));
+    // Unknown client id falls back to other keys.
+    assert_eq!(addr(mk_dgram(vec![DHCPOption::ClientId(b"\x00other".to_vec())])), Some(Ipv4Addr::new(10, 0, 0, 10)));
+}
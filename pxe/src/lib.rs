@@ -29,6 +29,12 @@ impl PXEBuilder {
         self.option(6, &[byte])
     }
 
+    // Prompt and show the menu, then ask the chosen boot server directly.
+    // Broadcast discovery is disabled, it would end up at the DHCP port.
+    pub fn start_menu(self) -> Self {
+        self.option(6, &[0b00000111])
+    }
+
     pub fn end(self) -> Self {
         self.option(255, &[])
     }
@@ -38,12 +44,14 @@ impl PXEBuilder {
         self.option(10, v.as_slice())
     }
 
-    pub fn menu_items(self, items: Vec<&'static str>) -> Self {
+    // Boot server type and description of each item.
+    pub fn menu_items(self, items: Vec<(u16, &str)>) -> Self {
         let bytes = items
             .iter()
-            .map(|item| {
+            .map(|(server_type, item)| {
                 // 2 bytes of server type, 1 byte of description length
-                let mut pre = vec![8, 0, item.len() as u8];
+                let mut pre = server_type.to_be_bytes().to_vec();
+                pre.push(item.len() as u8);
                 // description of specified size
                 pre.extend(item.as_bytes());
                 pre
@@ -55,18 +63,37 @@ impl PXEBuilder {
 
     // Specify IPs of boot servers
     pub fn boot_servers(self, ips: Vec<&Ipv4Addr>) -> Self {
+        self.typed_boot_servers(&[0], ips)
+    }
+
+    // Same boot servers for each of given server types, menu items refer to them by type.
+    pub fn typed_boot_servers(self, types: &[u16], ips: Vec<&Ipv4Addr>) -> Self {
         let ips_bytes = ips
             .iter()
             .map(|ip| ip.octets())
             .collect::<Vec<[u8; 4]>>()
             .concat();
 
-        let mut field = vec![0, 0, ips.len() as u8];
-        field.extend(ips_bytes);
+        let field = types
+            .iter()
+            .map(|server_type| {
+                let mut entry = server_type.to_be_bytes().to_vec();
+                entry.push(ips.len() as u8);
+                entry.extend(&ips_bytes);
+                entry
+            })
+            .collect::<Vec<Vec<u8>>>()
+            .concat();
 
         self.option(8, field.as_slice())
     }
 
+    // PXE_BOOT_ITEM, server type and layer of the item being answered.
+    pub fn boot_item(self, server_type: u16, layer: u16) -> Self {
+        let v = [server_type.to_be_bytes(), layer.to_be_bytes()].concat();
+        self.option(71, v.as_slice())
+    }
+
     pub fn mcast(self, addr: Ipv4Addr) -> Self {
         self.option(7, &addr.octets()[..])
     }
@@ -0,0 +1,367 @@
+use dhcp::{DHCPDgram, DHCPOption};
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+const DEFAULT_BOOTFILE: &str = "pxelinux.0";
+
+// Goes into 'sname' of every reply.
+const DEFAULT_SERVER_NAME: &str = "PXEServer";
+
+// PXE_BOOT_ITEM suboption of option 43, menu item picked by the client.
+const PXE_BOOT_ITEM: u8 = 71;
+
+// Client System Architecture (option 93), as registered by IANA.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ClientArch {
+    BiosX86,
+    EfiIa32,
+    EfiBc,
+    EfiX64,
+    EfiArm32,
+    EfiArm64,
+    HttpIa32,
+    HttpX64,
+    HttpArm32,
+    HttpArm64
+}
+
+impl ClientArch {
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            0 => Some(ClientArch::BiosX86),
+            6 => Some(ClientArch::EfiIa32),
+            7 => Some(ClientArch::EfiBc),
+            9 => Some(ClientArch::EfiX64),
+            10 => Some(ClientArch::EfiArm32),
+            11 => Some(ClientArch::EfiArm64),
+            15 => Some(ClientArch::HttpIa32),
+            16 => Some(ClientArch::HttpX64),
+            18 => Some(ClientArch::HttpArm32),
+            19 => Some(ClientArch::HttpArm64),
+            _ => None
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bios" => Some(ClientArch::BiosX86),
+            "efi-ia32" => Some(ClientArch::EfiIa32),
+            "efi-bc" => Some(ClientArch::EfiBc),
+            "efi-x64" => Some(ClientArch::EfiX64),
+            "efi-arm32" => Some(ClientArch::EfiArm32),
+            "efi-arm64" => Some(ClientArch::EfiArm64),
+            "http-ia32" => Some(ClientArch::HttpIa32),
+            "http-x64" => Some(ClientArch::HttpX64),
+            "http-arm32" => Some(ClientArch::HttpArm32),
+            "http-arm64" => Some(ClientArch::HttpArm64),
+            _ => None
+        }
+    }
+
+    // UEFI HTTP boot expects URL in 'filename' and 'HTTPClient' class in reply.
+    pub fn is_http(&self) -> bool {
+        matches!(self, ClientArch::HttpIa32
+                 | ClientArch::HttpX64
+                 | ClientArch::HttpArm32
+                 | ClientArch::HttpArm64)
+    }
+
+    /*
+     * Architecture of the client. Option 93 is authoritative, then the
+     * 'PXEClient:Arch:xxxxx' vendor class. Clients sending neither, but
+     * with UNDI 3.x in option 94, are UEFI machines, most likely x64.
+     */
+    pub fn of(dhcp: &DHCPDgram) -> Option<Self> {
+        if let Some(DHCPOption::ClientArch(archs)) = dhcp.option(DHCPOption::CLIENT_ARCH) {
+            return archs.first().and_then(|code| Self::from_code(*code));
+        }
+
+        if let Some(DHCPOption::VendorClass(class)) = dhcp.option(DHCPOption::VENDOR_CLASS) {
+            let arch = std::str::from_utf8(class).ok()
+                .and_then(|class| class.split(':').skip_while(|part| *part != "Arch").nth(1))
+                .and_then(|code| code.parse::<u16>().ok())
+                .and_then(Self::from_code);
+            if arch.is_some() {
+                return arch;
+            }
+        }
+
+        match dhcp.option(DHCPOption::CLIENT_NDI) {
+            Some(DHCPOption::ClientNdi(1, major, _)) if *major >= 3 => Some(ClientArch::EfiX64),
+            _ => None
+        }
+    }
+}
+
+// Entry of the PXE boot menu. Clients pick it by boot server type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MenuItem {
+    // 0 means local boot, the rest is up to us.
+    pub server_type: u16,
+    pub description: String,
+    // Replaces architecture specific file when the item is picked.
+    pub bootfile: Option<String>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootMenu {
+    pub prompt: String,
+    // Seconds before the first item is booted. 255 waits forever.
+    pub timeout: u8,
+    pub items: Vec<MenuItem>
+}
+
+#[derive(Clone)]
+pub struct BootFiles {
+    default: String,
+    by_arch: HashMap<ClientArch, String>,
+    // Handed to clients already running iPXE, so they don't chainload it again.
+    ipxe_script: Option<String>,
+    server_name: String,
+    // Where boot files are fetched from, when it's not us.
+    next_server: Option<Ipv4Addr>,
+    menu: Option<BootMenu>
+}
+
+impl BootFiles {
+    pub fn new(default: impl Into<String>) -> Self {
+        Self {
+            default: default.into(),
+            by_arch: HashMap::new(),
+            ipxe_script: None,
+            server_name: DEFAULT_SERVER_NAME.to_string(),
+            next_server: None,
+            menu: None
+        }
+    }
+
+    pub fn set_server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = name.into();
+        self
+    }
+
+    pub fn set_next_server(mut self, ip: Ipv4Addr) -> Self {
+        self.next_server = Some(ip);
+        self
+    }
+
+    pub fn set_menu(mut self, menu: BootMenu) -> Self {
+        self.menu = Some(menu);
+        self
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    // Boot server address, given our own one.
+    pub fn next_server(&self, own: Ipv4Addr) -> Ipv4Addr {
+        self.next_server.unwrap_or(own)
+    }
+
+    pub fn menu(&self) -> Option<&BootMenu> {
+        self.menu.as_ref()
+    }
+
+    pub fn set_ipxe_script(mut self, script: impl Into<String>) -> Self {
+        self.ipxe_script = Some(script.into());
+        self
+    }
+
+    pub fn set(mut self, arch: ClientArch, file: impl Into<String>) -> Self {
+        self.by_arch.insert(arch, file.into());
+        self
+    }
+
+    pub fn set_default(mut self, file: impl Into<String>) -> Self {
+        self.default = file.into();
+        self
+    }
+
+    // Parse 'arch=file' assignment, 'default=file' included.
+    pub fn assign(self, assignment: &str) -> Option<Self> {
+        let mut parts = assignment.splitn(2, '=');
+        let name = parts.next()?;
+        let file = parts.next().filter(|file| !file.is_empty())?;
+
+        if name == "default" {
+            return Some(self.set_default(file));
+        }
+        ClientArch::from_name(name).map(|arch| self.set(arch, file))
+    }
+
+    // HTTP clients take nothing but URL, so they don't fall back to the default file.
+    pub fn get(&self, arch: Option<ClientArch>) -> Option<&str> {
+        match arch {
+            Some(arch) if arch.is_http() => self.by_arch.get(&arch).map(String::as_str),
+            _ => Some(arch.and_then(|arch| self.by_arch.get(&arch)).unwrap_or(&self.default))
+        }
+    }
+
+    /*
+     * Boot file for the client. iPXE gets the script, so it doesn't chainload
+     * itself forever. Otherwise file fixed for this host wins, then the one
+     * of picked menu item, then a loader for client architecture. None when
+     * there is nothing to offer, i.e. HTTP client without URL for its arch.
+     */
+    pub fn select<'a>(&'a self, dhcp: &DHCPDgram, fixed: Option<&'a str>) -> Option<&'a str> {
+        if let Some(script) = self.ipxe_script.as_ref().filter(|_| is_ipxe(dhcp)) {
+            return Some(script);
+        }
+        if let Some(file) = fixed {
+            return Some(file);
+        }
+
+        let item = boot_item(dhcp)
+            .and_then(|server_type| self.menu.as_ref()?.items.iter().find(|item| item.server_type == server_type))
+            .and_then(|item| item.bootfile.as_ref());
+        match item {
+            Some(file) => Some(file),
+            None => self.get(ClientArch::of(dhcp))
+        }
+    }
+}
+
+// Server type of the boot item client asks for, from option 43.
+pub fn boot_item(dhcp: &DHCPDgram) -> Option<u16> {
+    let data = match dhcp.option(DHCPOption::VENDOR_SPECIFIC) {
+        Some(DHCPOption::VendorSpecific(data)) => data,
+        _ => return None
+    };
+
+    let mut rest = &data[..];
+    while let Some((&code, tail)) = rest.split_first() {
+        match code {
+            0 => { rest = tail; continue; },
+            255 => break,
+            _ => ()
+        }
+        let (&len, tail) = tail.split_first()?;
+        if len as usize > tail.len() {
+            return None;
+        }
+        let (value, tail) = tail.split_at(len as usize);
+        if code == PXE_BOOT_ITEM && value.len() >= 2 {
+            return Some(u16::from_be_bytes([value[0], value[1]]));
+        }
+        rest = tail;
+    }
+    None
+}
+
+// iPXE identifies itself with 'iPXE' user class (option 77) and sends option 175.
+pub fn is_ipxe(dhcp: &DHCPDgram) -> bool {
+    if let Some(DHCPOption::UserClass(class)) = dhcp.option(DHCPOption::USER_CLASS) {
+        if class == b"iPXE" || user_classes(class).any(|class| class == b"iPXE") {
+            return true;
+        }
+    }
+    dhcp.option(DHCPOption::IPXE_ENCAP).is_some()
+}
+
+// RFC 3004 user class is a list of length-prefixed entries.
+fn user_classes(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        let (len, tail) = rest.split_first()?;
+        let len = *len as usize;
+        if len == 0 || len > tail.len() {
+            return None;
+        }
+        let (class, tail) = tail.split_at(len);
+        rest = tail;
+        Some(class)
+    })
+}
+
+impl Default for BootFiles {
+    fn default() -> Self {
+        BootFiles::new(DEFAULT_BOOTFILE)
+            .set(ClientArch::BiosX86, "pxelinux.0")
+            .set(ClientArch::EfiIa32, "efi32/syslinux.efi")
+            .set(ClientArch::EfiBc, "efi64/syslinux.efi")
+            .set(ClientArch::EfiX64, "efi64/syslinux.efi")
+    }
+}
+
+#[test]
+fn client_arch_test() {
+    use dhcp::DHCPBody;
+
+    let mk_dgram = |option: DHCPOption| crate::test_dgram(DHCPBody::default(), vec![option]);
+
+    assert_eq!(ClientArch::of(&mk_dgram(DHCPOption::ClientArch(vec![7]))), Some(ClientArch::EfiBc));
+    assert_eq!(ClientArch::of(&mk_dgram(DHCPOption::VendorClass(b"PXEClient:Arch:00011:UNDI:003016".to_vec()))),
+               Some(ClientArch::EfiArm64));
+    assert_eq!(ClientArch::of(&mk_dgram(DHCPOption::ClientNdi(1, 3, 16))), Some(ClientArch::EfiX64));
+    assert_eq!(ClientArch::of(&mk_dgram(DHCPOption::ClientNdi(1, 2, 1))), None);
+
+    let files = BootFiles::default()
+        .assign("efi-x64=bootx64.efi").unwrap()
+        .assign("default=undionly.kpxe").unwrap();
+    assert!(BootFiles::default().assign("ppc=yaboot").is_none());
+
+    assert_eq!(files.get(Some(ClientArch::EfiX64)), Some("bootx64.efi"));
+    assert_eq!(files.get(Some(ClientArch::BiosX86)), Some("pxelinux.0"));
+    assert_eq!(files.get(Some(ClientArch::EfiArm64)), Some("undionly.kpxe"));
+    assert_eq!(files.get(Some(ClientArch::HttpX64)), None);
+    assert_eq!(files.get(None), Some("undionly.kpxe"));
+
+    let files = files.assign("http-x64=http://10.0.0.1/bootx64.efi").unwrap();
+    assert_eq!(files.get(Some(ClientArch::HttpX64)), Some("http://10.0.0.1/bootx64.efi"));
+}
+
+#[test]
+fn ipxe_test() {
+    use dhcp::DHCPBody;
+
+    let mk_dgram = |options: Vec<DHCPOption>| crate::test_dgram(DHCPBody::default(), options);
+
+    let plain = mk_dgram(vec![DHCPOption::ClientArch(vec![0])]);
+    let raw_class = mk_dgram(vec![DHCPOption::UserClass(b"iPXE".to_vec())]);
+    let rfc_class = mk_dgram(vec![DHCPOption::UserClass(b"\x03foo\x04iPXE".to_vec())]);
+    let encap = mk_dgram(vec![DHCPOption::ClientArch(vec![9]), DHCPOption::IpxeEncap(vec![177, 1, 1])]);
+
+    assert!(!is_ipxe(&plain));
+    assert!(is_ipxe(&raw_class));
+    assert!(is_ipxe(&rfc_class));
+    assert!(is_ipxe(&encap));
+
+    // Without script configured iPXE is treated like any other client.
+    assert_eq!(BootFiles::default().select(&encap, None), Some("efi64/syslinux.efi"));
+
+    let files = BootFiles::default().set_ipxe_script("http://10.0.0.1/boot.ipxe");
+    assert_eq!(files.select(&plain, None), Some("pxelinux.0"));
+    assert_eq!(files.select(&raw_class, None), Some("http://10.0.0.1/boot.ipxe"));
+    assert_eq!(files.select(&encap, None), Some("http://10.0.0.1/boot.ipxe"));
+    assert_eq!(files.select(&encap, Some("node1.0")), Some("http://10.0.0.1/boot.ipxe"));
+}
+
+#[test]
+fn menu_test() {
+    use dhcp::DHCPBody;
+
+    let mk_dgram = |vendor: &[u8]| crate::test_dgram(DHCPBody::default(), vec![
+        DHCPOption::ClientArch(vec![0]),
+        DHCPOption::VendorSpecific(vendor.to_vec())
+    ]);
+
+    let files = BootFiles::default().set_menu(BootMenu {
+        prompt: "Press F8".to_string(),
+        timeout: 5,
+        items: vec![
+            MenuItem { server_type: 0x8001, description: "Install".to_string(), bootfile: Some("install/pxelinux.0".to_string()) },
+            MenuItem { server_type: 0x8002, description: "Rescue".to_string(), bootfile: None }
+        ]
+    });
+
+    // Padding and other suboptions before the boot item are skipped.
+    assert_eq!(boot_item(&mk_dgram(&[0, 6, 1, 7, 71, 4, 0x80, 0x01, 0, 0, 255])), Some(0x8001));
+    assert_eq!(boot_item(&mk_dgram(&[71, 4, 0x80])), None);
+
+    assert_eq!(files.select(&mk_dgram(&[71, 4, 0x80, 0x01, 0, 0]), None), Some("install/pxelinux.0"));
+    assert_eq!(files.select(&mk_dgram(&[71, 4, 0x80, 0x02, 0, 0]), None), Some("pxelinux.0"));
+    assert_eq!(files.select(&mk_dgram(&[71, 4, 0x80, 0x03, 0, 0]), None), Some("pxelinux.0"));
+    assert_eq!(files.select(&mk_dgram(&[71, 4, 0x80, 0x01, 0, 0]), Some("node1.0")), Some("node1.0"));
+}
@@ -0,0 +1,751 @@
+use crate::{lease_time, parse_range, DEFAULT_LEASE_TIME, TFTP_PORT};
+use crate::boot::{BootFiles, BootMenu, ClientArch, MenuItem};
+
+use dhcp::lease::LeasePool;
+use dhcp::reservation::{Reservation, Reservations};
+use serde::{de, Deserialize, Deserializer};
+use tftp::{BlockRollover, FsProvider, OverwritePolicy, PxeHost, PxelinuxProvider, TFTPServerConfig, MAX_BLKSIZE, MIN_BLKSIZE};
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::{fmt, fs, io};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Room left in 'sname' and 'filename' after terminating NUL.
+const MAX_SERVER_NAME: usize = 63;
+const MAX_BOOTFILE: usize = 127;
+
+/*
+ * Whole server described by a single file, TOML or YAML depending on the
+ * extension:
+ *
+ *   [server]
+ *   listen = "10.0.0.1:67"        # one interface only
+ *   name = "PXEServer"            # 'sname' in replies
+ *   boot_server = "10.0.0.2"      # 'next server', defaults to listen address
+ *
+ *   [dhcp]
+ *   mode = "pool"                 # or "proxy"
+ *   range = "10.0.0.100-10.0.0.200"
+ *   lease_time = 3600
+ *   lease_file = "/var/lib/pxe-server/leases"
+ *
+ *   [boot]
+ *   default = "pxelinux.0"
+ *   ipxe_script = "http://10.0.0.1/boot.ipxe"
+ *   arch = { efi-x64 = "efi64/syslinux.efi" }
+ *
+ *   [menu]
+ *   prompt = "Press F8 for boot menu"
+ *   timeout = 10
+ *   [[menu.item]]
+ *   type = 0x8001
+ *   description = "Install"
+ *   bootfile = "install/pxelinux.0"
+ *
+ *   [tftp]
+ *   root = "/srv/tftp"
+ *   max_blksize = 1468            # and the rest of TFTPServerConfig
+ *   pxelinux_template = "DEFAULT {{hostname}}\n.."   # config for each [[host]] with mac, else only ones with kernel
+ *   pxelinux_default = "DEFAULT local\n.."           # served as pxelinux.cfg/default
+ *
+ *   [[host]]
+ *   mac = "88:99:aa:bb:cc:dd"     # and/or client_id = "01:88:99:..", uuid = "..."
+ *   ip = "10.0.0.10"
+ *   hostname = "node1"
+ *   bootfile = "node1/pxelinux.0"
+ *   kernel = "vmlinuz"            # pxelinux config variables
+ *   append = "initrd=initrd.img"
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    server: ServerSection,
+    #[serde(default)]
+    dhcp: DhcpSection,
+    #[serde(default)]
+    boot: BootSection,
+    menu: Option<MenuSection>,
+    tftp: Option<TftpSection>,
+    #[serde(default)]
+    host: Vec<HostSection>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServerSection {
+    #[serde(deserialize_with = "listen_addr")]
+    listen: SocketAddrV4,
+    name: Option<String>,
+    boot_server: Option<Ipv4Addr>
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum DhcpMode {
+    #[default]
+    Pool,
+    Proxy
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct DhcpSection {
+    #[serde(default)]
+    mode: DhcpMode,
+    range: Option<String>,
+    lease_time: Option<u64>,
+    lease_file: Option<PathBuf>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct BootSection {
+    default: Option<String>,
+    ipxe_script: Option<String>,
+    #[serde(default)]
+    arch: BTreeMap<String, String>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MenuSection {
+    prompt: String,
+    timeout: u8,
+    item: Vec<MenuItemSection>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MenuItemSection {
+    #[serde(rename = "type")]
+    server_type: u16,
+    description: String,
+    bootfile: Option<String>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TftpSection {
+    root: PathBuf,
+    listen: Option<SocketAddrV4>,
+    max_blksize: Option<u16>,
+    max_windowsize: Option<u16>,
+    timeout: Option<u64>,
+    retries: Option<u8>,
+    max_transfers: Option<usize>,
+    rollover: Option<u8>,
+    upload_dir: Option<PathBuf>,
+    overwrite: Option<Overwrite>,
+    max_upload_size: Option<u64>,
+    pxelinux_template: Option<String>,
+    pxelinux_default: Option<String>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Overwrite {
+    Refuse,
+    Replace
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostSection {
+    mac: Option<String>,
+    client_id: Option<String>,
+    uuid: Option<String>,
+    ip: Option<Ipv4Addr>,
+    hostname: Option<String>,
+    bootfile: Option<String>,
+    kernel: Option<String>,
+    append: Option<String>
+}
+
+// Machine with reserved address, boot file or pxelinux config of its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Host {
+    pub mac: Option<[u8; 6]>,
+    pub client_id: Option<Vec<u8>>,
+    pub uuid: Option<[u8; 16]>,
+    pub ip: Option<Ipv4Addr>,
+    pub hostname: Option<String>,
+    pub bootfile: Option<String>,
+    pub kernel: Option<String>,
+    pub append: Option<String>
+}
+
+pub struct Config {
+    pub listen: SocketAddrV4,
+    // Without pool we act as proxyDHCP.
+    pub pool: Option<LeasePool>,
+    // Journal keeping pool leases across restarts.
+    pub lease_file: Option<PathBuf>,
+    pub boot: BootFiles,
+    pub tftp: Option<TFTPServerConfig>,
+    pub hosts: Vec<Host>,
+    pub pxelinux_template: Option<String>,
+    pub pxelinux_default: Option<String>
+}
+
+// What's wrong and where. Key is a path like 'host[2].mac'.
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { key: key.into(), message: message.into() }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            return write!(f, "{}", self.message);
+        }
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Self::from_yaml(&text),
+            _ => Self::from_toml(&text)
+        };
+
+        config.map_err(|err| {
+            let msg = format!("{}: {}", path.display(), err);
+            io::Error::new(ErrorKind::InvalidData, msg)
+        })
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        let deserializer = toml::Deserializer::parse(text)
+            .map_err(|err| ConfigError::new("", err.to_string()))?;
+        let file = serde_path_to_error::deserialize(deserializer)
+            .map_err(|err| ConfigError::new(key_of(err.path()), err.inner().message()))?;
+        Self::from_file(file)
+    }
+
+    pub fn from_yaml(text: &str) -> Result<Self, ConfigError> {
+        let file = serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(text))
+            .map_err(|err| ConfigError::new(key_of(err.path()), err.inner().to_string()))?;
+        Self::from_file(file)
+    }
+
+    // Hosts as seen by DHCP.
+    pub fn reservations(&self) -> Reservations {
+        self.hosts.iter().fold(Reservations::new(), |hosts, host| {
+            hosts.host(Reservation {
+                mac: host.mac,
+                client_id: host.client_id.clone(),
+                uuid: host.uuid,
+                addr: host.ip,
+                hostname: host.hostname.clone(),
+                bootfile: host.bootfile.clone()
+            })
+        })
+    }
+
+    /*
+     * Files served over TFTP. Hosts known by MAC get their pxelinux configs
+     * rendered, everything else comes from the root. Host with neither kernel
+     * nor template of our own has nothing to render, its config is left to
+     * the root as well.
+     */
+    pub fn tftp_files(&self) -> Option<PxelinuxProvider> {
+        let tftp = self.tftp.as_ref()?;
+
+        let mut files = PxelinuxProvider::new(FsProvider::new(&tftp.root));
+        if let Some(template) = &self.pxelinux_template {
+            files = files.template(template.as_str());
+        }
+        if let Some(default) = &self.pxelinux_default {
+            files = files.default_config(default.as_str());
+        }
+
+        Some(self.hosts.iter().fold(files, |files, host| {
+            let mac = match host.mac {
+                Some(mac) if host.kernel.is_some() || self.pxelinux_template.is_some() => mac,
+                _ => return files
+            };
+            let mut pxe_host = PxeHost::new(mac);
+            if let Some(uuid) = host.uuid {
+                pxe_host = pxe_host.uuid(uuid);
+            }
+            if let Some(ip) = host.ip {
+                pxe_host = pxe_host.ip(ip);
+            }
+            let vars = [("hostname", &host.hostname), ("kernel", &host.kernel), ("append", &host.append)];
+            for (name, value) in vars.iter() {
+                if let Some(value) = value {
+                    pxe_host = pxe_host.var(*name, value.as_str());
+                }
+            }
+            files.host(pxe_host)
+        }))
+    }
+
+    fn from_file(file: ConfigFile) -> Result<Self, ConfigError> {
+        let listen = file.server.listen;
+        let (tftp, pxelinux_template, pxelinux_default) = match file.tftp {
+            Some(section) => {
+                let template = section.pxelinux_template.clone();
+                let default = section.pxelinux_default.clone();
+                (Some(tftp_config(section, *listen.ip())?), template, default)
+            },
+            None => (None, None, None)
+        };
+
+        let hosts = file.host.into_iter()
+            .enumerate()
+            .map(|(idx, host)| self::host(idx, host))
+            .collect::<Result<Vec<Host>, ConfigError>>()?;
+        for (idx, host) in hosts.iter().enumerate() {
+            let taken = hosts[..idx].iter().position(|other| host.ip.is_some() && other.ip == host.ip);
+            if let Some(other) = taken {
+                return Err(ConfigError::new(format!("host[{}].ip", idx), format!("already reserved for host[{}]", other)));
+            }
+        }
+
+        // Reserved addresses inside the pool are not for anyone else.
+        let mut pool = pool(&file.dhcp)?;
+        if let Some(pool) = pool.as_mut() {
+            hosts.iter().filter_map(|host| host.ip).for_each(|ip| pool.reserve(ip));
+        }
+
+        Ok(Config {
+            listen,
+            pool,
+            lease_file: file.dhcp.lease_file,
+            boot: boot_files(&file.server, &file.boot, file.menu)?,
+            tftp,
+            hosts,
+            pxelinux_template,
+            pxelinux_default
+        })
+    }
+}
+
+fn pool(dhcp: &DhcpSection) -> Result<Option<LeasePool>, ConfigError> {
+    if dhcp.mode == DhcpMode::Proxy {
+        let unused = [("dhcp.range", dhcp.range.is_some()),
+                      ("dhcp.lease_time", dhcp.lease_time.is_some()),
+                      ("dhcp.lease_file", dhcp.lease_file.is_some())];
+        if let Some((key, _)) = unused.iter().find(|(_, set)| *set) {
+            return Err(ConfigError::new(*key, "not used in proxy mode"));
+        }
+        return Ok(None);
+    }
+
+    let range = dhcp.range.as_ref()
+        .ok_or_else(|| ConfigError::new("dhcp.range", "required in pool mode"))?;
+    let (first, last) = parse_range(range)
+        .ok_or_else(|| ConfigError::new("dhcp.range", format!("expected 'x.x.x.x-y.y.y.y', got '{}'", range)))?;
+
+    let lease_time = lease_time(dhcp.lease_time.unwrap_or(DEFAULT_LEASE_TIME))
+        .ok_or_else(|| ConfigError::new("dhcp.lease_time", format!("expected 1 to {} seconds", u32::MAX)))?;
+    Ok(Some(LeasePool::new(first, last, lease_time)))
+}
+
+fn boot_files(server: &ServerSection, boot: &BootSection, menu: Option<MenuSection>) -> Result<BootFiles, ConfigError> {
+    let mut files = BootFiles::default();
+
+    if let Some(name) = &server.name {
+        check_len("server.name", name, MAX_SERVER_NAME)?;
+        files = files.set_server_name(name.as_str());
+    }
+    if let Some(ip) = server.boot_server {
+        files = files.set_next_server(ip);
+    }
+
+    if let Some(default) = &boot.default {
+        check_len("boot.default", default, MAX_BOOTFILE)?;
+        files = files.set_default(default.as_str());
+    }
+    if let Some(script) = &boot.ipxe_script {
+        check_len("boot.ipxe_script", script, MAX_BOOTFILE)?;
+        files = files.set_ipxe_script(script.as_str());
+    }
+    for (name, file) in boot.arch.iter() {
+        let key = format!("boot.arch.{}", name);
+        let arch = ClientArch::from_name(name)
+            .ok_or_else(|| ConfigError::new(key.as_str(), "unknown architecture"))?;
+        check_len(&key, file, MAX_BOOTFILE)?;
+        files = files.set(arch, file.as_str());
+    }
+
+    match menu {
+        Some(menu) => Ok(files.set_menu(boot_menu(menu)?)),
+        None => Ok(files)
+    }
+}
+
+fn boot_menu(menu: MenuSection) -> Result<BootMenu, ConfigError> {
+    // Prompt shares option length byte with the timeout.
+    check_len("menu.prompt", &menu.prompt, 254)?;
+    if menu.item.is_empty() {
+        return Err(ConfigError::new("menu.item", "at least one item required"));
+    }
+
+    let items = menu.item.into_iter()
+        .enumerate()
+        .map(|(idx, item)| {
+            check_len(&format!("menu.item[{}].description", idx), &item.description, 255)?;
+            if let Some(file) = &item.bootfile {
+                check_len(&format!("menu.item[{}].bootfile", idx), file, MAX_BOOTFILE)?;
+            }
+            Ok(MenuItem { server_type: item.server_type, description: item.description, bootfile: item.bootfile })
+        })
+        .collect::<Result<Vec<MenuItem>, ConfigError>>()?;
+
+    // All items are listed in a single option 9, and each one with its own
+    // type also takes type, count and address of the boot server in option 8.
+    let descriptions = items.iter().map(|item| 3 + item.description.len()).sum::<usize>();
+    if descriptions > 255 {
+        return Err(ConfigError::new("menu.item", "descriptions too long to fit into one option"));
+    }
+    let servers = 7 * items.iter().filter(|item| item.server_type != 0).count();
+    if servers > 255 {
+        return Err(ConfigError::new("menu.item", "too many items to list their boot servers in one option"));
+    }
+
+    // Discovery control, boot servers, items, prompt, boot item and end in
+    // a single option 43, PXE ROMs don't join split ones.
+    let vendor = 3 + (2 + servers) + (2 + descriptions) + (3 + menu.prompt.len()) + 6 + 1;
+    if vendor > 255 {
+        return Err(ConfigError::new("menu.item", "menu too long to fit into vendor options"));
+    }
+
+    Ok(BootMenu { prompt: menu.prompt, timeout: menu.timeout, items })
+}
+
+fn tftp_config(tftp: TftpSection, ip: Ipv4Addr) -> Result<TFTPServerConfig, ConfigError> {
+    let mut config = TFTPServerConfig {
+        root: tftp.root,
+        addr: tftp.listen.unwrap_or_else(|| SocketAddrV4::new(ip, TFTP_PORT)),
+        upload_dir: tftp.upload_dir,
+        ..Default::default()
+    };
+
+    if let Some(blksize) = tftp.max_blksize {
+        config.max_blksize = check_blksize("tftp.max_blksize", blksize)?;
+    }
+    if let Some(windowsize) = tftp.max_windowsize {
+        if windowsize == 0 {
+            return Err(ConfigError::new("tftp.max_windowsize", "must be positive"));
+        }
+        config.max_windowsize = windowsize;
+    }
+    if let Some(timeout) = tftp.timeout {
+        // Same range clients may ask for with timeout option (RFC 2349).
+        if !(1..=255).contains(&timeout) {
+            return Err(ConfigError::new("tftp.timeout", "expected 1-255 seconds"));
+        }
+        config.timeout = Duration::from_secs(timeout);
+    }
+    if let Some(retries) = tftp.retries {
+        config.retries = retries;
+    }
+    if let Some(max_transfers) = tftp.max_transfers {
+        if max_transfers == 0 {
+            return Err(ConfigError::new("tftp.max_transfers", "must be positive"));
+        }
+        config.max_transfers = max_transfers;
+    }
+    config.block_rollover = match tftp.rollover {
+        None | Some(0) => BlockRollover::Zero,
+        Some(1) => BlockRollover::One,
+        Some(_) => return Err(ConfigError::new("tftp.rollover", "expected 0 or 1"))
+    };
+    if let Some(overwrite) = tftp.overwrite {
+        config.overwrite = match overwrite {
+            Overwrite::Refuse => OverwritePolicy::Refuse,
+            Overwrite::Replace => OverwritePolicy::Replace
+        };
+    }
+    if let Some(size) = tftp.max_upload_size {
+        config.max_upload_size = size;
+    }
+
+    Ok(config)
+}
+
+fn host(idx: usize, host: HostSection) -> Result<Host, ConfigError> {
+    let key = |name: &str| format!("host[{}].{}", idx, name);
+    let invalid = |name: &str, expected: &str, value: &str| {
+        ConfigError::new(key(name), format!("expected '{}', got '{}'", expected, value))
+    };
+
+    if host.mac.is_none() && host.client_id.is_none() && host.uuid.is_none() {
+        return Err(ConfigError::new(format!("host[{}]", idx), "needs mac, client_id or uuid"));
+    }
+    let mac = match &host.mac {
+        Some(mac) => Some(parse_mac(mac).ok_or_else(|| invalid("mac", "aa:bb:cc:dd:ee:ff", mac))?),
+        None => None
+    };
+    let client_id = match &host.client_id {
+        Some(id) => Some(parse_hex(id).ok_or_else(|| invalid("client_id", "01:aa:bb:cc:dd:ee:ff", id))?),
+        None => None
+    };
+    let uuid = match &host.uuid {
+        Some(uuid) => Some(parse_uuid(uuid).ok_or_else(|| invalid("uuid", "xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx", uuid))?),
+        None => None
+    };
+    if let Some(hostname) = &host.hostname {
+        check_len(&key("hostname"), hostname, 255)?;
+    }
+    if let Some(bootfile) = &host.bootfile {
+        check_len(&key("bootfile"), bootfile, MAX_BOOTFILE)?;
+    }
+
+    Ok(Host {
+        mac,
+        client_id,
+        uuid,
+        ip: host.ip,
+        hostname: host.hostname,
+        bootfile: host.bootfile,
+        kernel: host.kernel,
+        append: host.append
+    })
+}
+
+// Hex bytes separated by ':' or '-'.
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.split([':', '-'])
+        .map(|part| match part.len() {
+            2 => u8::from_str_radix(part, 16).ok(),
+            _ => None
+        })
+        .collect()
+}
+
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    parse_hex(mac)?.try_into().ok()
+}
+
+// 32 hex digits, dashes anywhere in between.
+fn parse_uuid(uuid: &str) -> Option<[u8; 16]> {
+    let digits = uuid.replace('-', "");
+    if digits.len() != 32 || !digits.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; 16];
+    for (idx, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[idx * 2..idx * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn check_len(key: &str, value: &str, max: usize) -> Result<(), ConfigError> {
+    if value.len() > max {
+        return Err(ConfigError::new(key, format!("longer than {} bytes", max)));
+    }
+    Ok(())
+}
+
+fn check_blksize(key: &str, blksize: u16) -> Result<u16, ConfigError> {
+    if !(MIN_BLKSIZE..=MAX_BLKSIZE).contains(&blksize) {
+        return Err(ConfigError::new(key, format!("expected {}-{}", MIN_BLKSIZE, MAX_BLKSIZE)));
+    }
+    Ok(blksize)
+}
+
+/*
+ * Single 'x.x.x.x:pp'. Replies carry our address as server id, and the
+ * pool is meant for one subnet, so a list of interfaces is refused by
+ * name rather than as type mismatch.
+ */
+fn listen_addr<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SocketAddrV4, D::Error> {
+    struct Addr;
+
+    impl<'de> de::Visitor<'de> for Addr {
+        type Value = SocketAddrV4;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("address and port like '10.0.0.1:67'")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<SocketAddrV4, E> {
+            value.parse().map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, _: A) -> Result<SocketAddrV4, A::Error> {
+            Err(de::Error::custom("only one address supported, run a server per interface"))
+        }
+    }
+
+    deserializer.deserialize_any(Addr)
+}
+
+// Path of the value serde choked on, nothing for the top level.
+fn key_of(path: &serde_path_to_error::Path) -> String {
+    match path.to_string().as_str() {
+        "." => String::new(),
+        key => key.to_string()
+    }
+}
+
+#[test]
+fn config_test() {
+    let config = Config::from_toml(r#"
+        [server]
+        listen = "10.0.0.1:67"
+        name = "boot"
+        boot_server = "10.0.0.2"
+
+        [dhcp]
+        range = "10.0.0.100-10.0.0.200"
+        lease_time = 600
+        lease_file = "/var/lib/pxe-server/leases"
+
+        [boot]
+        default = "undionly.kpxe"
+        arch = { efi-x64 = "ipxe.efi" }
+
+        [menu]
+        prompt = "Press F8"
+        timeout = 5
+        [[menu.item]]
+        type = 0x8001
+        description = "Install"
+        bootfile = "install/pxelinux.0"
+
+        [tftp]
+        root = "/srv/tftp"
+        max_blksize = 1024
+        rollover = 1
+        overwrite = "replace"
+
+        [[host]]
+        mac = "88:99:aa:bb:cc:dd"
+        ip = "10.0.0.100"
+        hostname = "node1"
+
+        [[host]]
+        client_id = "00:6e:32"
+        uuid = "00112233-4455-6677-8899-aabbccddeeff"
+        bootfile = "node2.efi"
+    "#).unwrap();
+
+    assert_eq!(config.listen, "10.0.0.1:67".parse().unwrap());
+    let pool = config.pool.as_ref().unwrap();
+    assert!(pool.contains(Ipv4Addr::new(10, 0, 0, 200)));
+    assert_eq!(pool.lease_time(), Duration::from_secs(600));
+    assert_eq!(config.lease_file.as_deref(), Some(Path::new("/var/lib/pxe-server/leases")));
+
+    assert_eq!(config.boot.server_name(), "boot");
+    assert_eq!(config.boot.next_server(Ipv4Addr::new(10, 0, 0, 1)), Ipv4Addr::new(10, 0, 0, 2));
+    assert_eq!(config.boot.get(Some(ClientArch::EfiX64)), Some("ipxe.efi"));
+    assert_eq!(config.boot.get(Some(ClientArch::BiosX86)), Some("pxelinux.0"));
+    assert_eq!(config.boot.get(None), Some("undionly.kpxe"));
+    assert_eq!(config.boot.menu().unwrap().items[0].server_type, 0x8001);
+
+    let tftp = config.tftp.as_ref().unwrap();
+    assert_eq!(tftp.addr, "10.0.0.1:69".parse().unwrap());
+    assert_eq!(tftp.max_blksize, 1024);
+    assert_eq!(tftp.block_rollover, BlockRollover::One);
+    assert_eq!(tftp.overwrite, OverwritePolicy::Replace);
+    assert_eq!(config.hosts[0].mac, Some([0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD]));
+    assert_eq!(config.hosts[1].client_id, Some(vec![0x00, 0x6E, 0x32]));
+    assert_eq!(config.hosts[1].uuid.map(|uuid| uuid[15]), Some(0xFF));
+    assert_eq!(config.reservations().iter().count(), 2);
+
+    // Reserved address is skipped by the pool.
+    let mut pool = config.pool.unwrap();
+    assert_eq!(pool.offer(b"other", None), Some(Ipv4Addr::new(10, 0, 0, 101)));
+
+    let proxy = Config::from_yaml("
+        server:
+          listen: 10.0.0.1:67
+        dhcp:
+          mode: proxy
+    ").unwrap();
+    assert!(proxy.pool.is_none());
+    assert!(proxy.tftp.is_none());
+    assert_eq!(proxy.boot.server_name(), "PXEServer");
+}
+
+#[test]
+fn config_error_test() {
+    let error = |text: &str| Config::from_toml(&format!("[server]\nlisten = \"10.0.0.1:67\"\n{}", text))
+        .err()
+        .map(|err| err.key);
+
+    assert_eq!(error("[dhcp]\nrange = \"10.0.0.1\"").as_deref(), Some("dhcp.range"));
+    assert_eq!(error("[dhcp]\nmode = \"proxy\"\nlease_time = 60").as_deref(), Some("dhcp.lease_time"));
+    assert_eq!(error("[dhcp]\nrange = \"10.0.0.50-10.0.0.60\"\nlease_time = 4294967296").as_deref(), Some("dhcp.lease_time"));
+    assert_eq!(error("[dhcp]\nmode = \"proxy\"\nlease_file = \"leases\"").as_deref(), Some("dhcp.lease_file"));
+    assert_eq!(error("[dhcp]\nmode = \"relay\"").as_deref(), Some("dhcp.mode"));
+    assert_eq!(error("[dhcp]\nmode = \"proxy\"\n[boot.arch]\nppc = \"yaboot\"").as_deref(), Some("boot.arch.ppc"));
+    assert_eq!(error("[dhcp]\nmode = \"proxy\"\n[tftp]\nroot = \"/srv\"\nmax_blksize = 4").as_deref(), Some("tftp.max_blksize"));
+    assert_eq!(error("[dhcp]\nmode = \"proxy\"\n[tftp]\nroot = \"/srv\"\nretries = -1").as_deref(), Some("tftp.retries"));
+    assert_eq!(error("[dhcp]\nmode = \"proxy\"\n[[host]]\nmac = \"88:99:aa:bb:cc:dd\"\n[[host]]\nmac = \"88:99\"").as_deref(),
+               Some("host[1].mac"));
+    assert_eq!(error("[dhcp]\nmode = \"proxy\"\n[[host]]\nuuid = \"0011\"").as_deref(), Some("host[0].uuid"));
+    assert_eq!(error("[dhcp]\nmode = \"proxy\"\n[[host]]\nip = \"10.0.0.5\"").as_deref(), Some("host[0]"));
+    assert_eq!(error("[dhcp]\nmode = \"proxy\"\n[[host]]\nmac = \"88:99:aa:bb:cc:dd\"\nip = \"10.0.0.5\"\n[[host]]\nclient_id = \"01\"\nip = \"10.0.0.5\"").as_deref(),
+               Some("host[1].ip"));
+
+    let item = |idx: usize| format!("[[menu.item]]\ntype = {}\ndescription = \"{}\"\n", 0x8000 + idx, idx);
+    let menu = |count: usize| format!("[dhcp]\nmode = \"proxy\"\n[menu]\nprompt = \"Press F8\"\ntimeout = 5\n{}",
+                                      (0..count).map(item).collect::<String>());
+    assert_eq!(error(&menu(20)), None);
+    assert_eq!(error(&menu(21)).as_deref(), Some("menu.item"));
+    assert_eq!(error(&menu(40)).as_deref(), Some("menu.item"));
+
+    let hostname = format!("[dhcp]\nmode = \"proxy\"\n[[host]]\nmac = \"88:99:aa:bb:cc:dd\"\nhostname = \"{}\"", "a".repeat(256));
+    assert_eq!(error(&hostname).as_deref(), Some("host[0].hostname"));
+
+    let err = Config::from_yaml("server:\n  listen: 10.0.0.1\n").err().unwrap();
+    assert_eq!(err.key, "server.listen");
+    let err = Config::from_toml("[server]\nlisten = [\"10.0.0.1:67\", \"10.0.1.1:67\"]").err().unwrap();
+    assert_eq!(err.key, "server.listen");
+    assert!(err.message.starts_with("only one address"));
+    let err = Config::from_toml("[server]\nlisten = \"10.0.0.1:67\"\n[dhcp]\nmode = \"proxy\"\nlease = 60").err().unwrap();
+    assert_eq!(err.key, "dhcp.lease");
+    assert!(err.message.starts_with("unknown field"));
+}
+
+#[test]
+fn tftp_files_test() {
+    use std::io::Read;
+    use tftp::FileProvider;
+
+    let dir = std::env::temp_dir().join(format!("pxe-config-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("pxelinux.cfg")).unwrap();
+    std::fs::write(dir.join("pxelinux.cfg/01-88-99-aa-bb-cc-dd"), "DEFAULT disk").unwrap();
+
+    let load = |template: &str| Config::from_toml(&format!(
+        "[server]\nlisten = \"10.0.0.1:67\"\n[dhcp]\nmode = \"proxy\"\n[tftp]\nroot = {:?}\n{}\n\
+         [[host]]\nmac = \"88:99:aa:bb:cc:dd\"\nip = \"10.0.0.5\"\n\
+         [[host]]\nmac = \"88:99:aa:bb:cc:ee\"\nkernel = \"vmlinuz\"\n",
+        dir.to_str().unwrap(), template)).unwrap();
+    let read = |config: &Config, name: &str| {
+        let (mut reader, _) = config.tftp_files().unwrap().open(name).unwrap();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        text
+    };
+
+    // Host only reserving an address falls through to the file on disk.
+    let config = load("");
+    assert_eq!(read(&config, "pxelinux.cfg/01-88-99-aa-bb-cc-dd"), "DEFAULT disk");
+    assert!(read(&config, "pxelinux.cfg/01-88-99-aa-bb-cc-ee").contains("KERNEL vmlinuz"));
+
+    // Own template is rendered for every host.
+    let config = load("pxelinux_template = \"LABEL {{ip}}\"");
+    assert_eq!(read(&config, "pxelinux.cfg/01-88-99-aa-bb-cc-dd"), "LABEL 10.0.0.5");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
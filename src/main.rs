@@ -1,8 +1,21 @@
-use dhcp::{DHCPDgram, DHCPDgramBuilder};
+mod boot;
+mod config;
+
+use dhcp::{DHCPBody, DHCPDgram, DHCPDgramBuilder, DHCPOption, MessageType, BODY_LEN};
+use dhcp::journal;
+use dhcp::lease::{self, LeasePool};
+use dhcp::reservation::{Reservation, Reservations};
 use pxe::{PXEBuilder};
+use tftp::{TFTPServer, TFTPServerConfig};
+use boot::{BootFiles, ClientArch};
+use config::Config;
 
-use std::{env, io};
+use std::{env, io, thread};
 use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
 use std::net::{SocketAddr,
                SocketAddrV4,
                UdpSocket,
@@ -12,59 +25,132 @@ use std::net::{SocketAddr,
 const BOOT_REQUEST: u8 = 1;
 const BOOT_REPLY: u8 = 2;
 
-// Types
-const DISCOVER: u8 = 1;
-const OFFER: u8 = 2;
-const REQUEST: u8 = 3;
-const ACK: u8 = 5;
+const DEFAULT_LEASE_TIME: u64 = 3600;
+
+// PXE boot server port, used by clients after proxyDHCP offer.
+const PROXY_PORT: u16 = 4011;
 
-// Options
-const VENDOR_OPTIONS: u8 = 43;
-const MESSAGE_TYPE: u8 = 53;
-const SERVER_ID: u8 = 54;
-const CLASS_ID: u8 = 60;
-const CLIENT_MAC: u8 = 97;
+const TFTP_PORT: u16 = 69;
+
+// How often blocked sockets check whether server is shutting down.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
 
 fn main() -> std::io::Result<()> {
     // Broadcast, UDP 68. For server responses.
     let broadcast = SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), 68);
 
-    // Get server address
-    let argv = env::args().collect::<Vec<String>>();
-    let addr = argv.get(1)
-        .and_then(|addr| addr.parse::<SocketAddrV4>().ok())
-        .ok_or({
-            let msg = format!("You must specify address and port to listen on. Example: {} x.x.x.x:pp",
-                              argv.get(0).unwrap());
-            io::Error::new(ErrorKind::InvalidInput, msg)
-        })?;
+    // Print leases kept in the journal and quit. Safe while server is running.
+    let mut argv = env::args().collect::<Vec<String>>();
+    if let Some(path) = take_flag(&mut argv, "--leases") {
+        return show_leases(Path::new(&path));
+    }
+
+    // Whole setup from a file, or from command line.
+    let config = match take_flag(&mut argv, "--config") {
+        Some(path) => Config::load(Path::new(&path))?,
+        None => args_config(argv)?
+    };
+    let tftp_files = config.tftp_files();
+    let hosts = config.reservations();
+    let Config { listen: addr, pool, lease_file, boot, tftp, .. } = config;
+
+    // Leases given out before restart stay with their clients.
+    let mut pool = match (pool, lease_file) {
+        (Some(pool), Some(path)) => {
+            println!("Keeping leases in {:?}...", path);
+            Some(pool.persist(path)?)
+        },
+        (pool, _) => pool
+    };
 
     // Setup socket
-    let socket = UdpSocket::bind(&addr)?;
+    let socket = UdpSocket::bind(addr)?;
     socket.set_broadcast(true)?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
     println!("Listening on {}...", addr);
 
-    // Main server loop
-    loop {
-        let (dhcp, from) = listen(&socket);
+    // Every socket is bound before any of them is served, so one failing stops startup.
+    // In proxy mode clients come back to port 4011 for the boot file, with menu for the picked item.
+    let boot_socket = match pool {
+        Some(_) if boot.menu().is_none() => None,
+        _ => {
+            let boot_addr = SocketAddrV4::new(*addr.ip(), PROXY_PORT);
+            let boot_socket = UdpSocket::bind(boot_addr)?;
+            boot_socket.set_read_timeout(Some(POLL_INTERVAL))?;
+            println!("Boot server listening on {}...", boot_addr);
+            Some(boot_socket)
+        }
+    };
+    let tftp = match tftp {
+        Some(config) => {
+            let tftp_socket = UdpSocket::bind(config.addr)?;
+            println!("Serving {:?} over TFTP on {}...", config.root, config.addr);
+            let tftp = match tftp_files {
+                Some(files) => TFTPServer::new(config).files(files),
+                None => TFTPServer::new(config)
+            };
+            Some((tftp, tftp_socket))
+        },
+        None => None
+    };
+
+    // Ctrl-C stops all servers. TFTP lets transfers in progress finish first.
+    let running = Arc::new(AtomicBool::new(true));
+    {
+        let running = running.clone();
+        ctrlc::set_handler(move || running.store(false, Ordering::SeqCst))
+            .map_err(io::Error::other)?;
+    }
+
+    let mut servers = Vec::new();
+    if let Some(boot_socket) = boot_socket {
+        let (boot, hosts, running) = (boot.clone(), hosts.clone(), running.clone());
+        servers.push(thread::spawn(move || serve_boot(&addr, &boot_socket, &boot, &hosts, &running)));
+    }
+    if let Some((mut tftp, tftp_socket)) = tftp {
+        let running = running.clone();
+        servers.push(thread::spawn(move || {
+            if let Err(err) = tftp.serve(tftp_socket, &running) {
+                println!("TFTP server failed: {}", err);
+            }
+        }));
+    }
 
-        // Convert for BE to LE
-        let dhcp = dhcp.swap_endianess();
+    // Main server loop
+    while let Some((dhcp, from)) = listen(&socket, &running) {
         let body = dhcp.body;
 
         // Server ignores replies.
-        if body.op == BOOT_REPLY {
+        if body.op != BOOT_REQUEST {
             continue;
         }
 
         // Try to create response for request.
-        let res = match dhcp.option(MESSAGE_TYPE) {
-            Some(&[DISCOVER]) => {
+        let res = match dhcp.message_type() {
+            Some(MessageType::Discover) => {
                 println!("DHCP_DISCOVER FROM: {}", from);
-                discover(&addr, dhcp)
+                match pool.as_mut() {
+                    Some(pool) => discover(&addr, dhcp, pool, &boot, &hosts),
+                    None => proxy_discover(&addr, dhcp, &boot, &hosts)
+                }
             },
-            Some(&[REQUEST]) => {
+            // Addresses are not ours to manage in proxy mode.
+            Some(MessageType::Request) => {
                 println!("DHCP_REQUEST FROM: {}", from);
+                pool.as_mut().and_then(|pool| request(&addr, dhcp, pool, &boot, &hosts))
+            },
+            Some(MessageType::Release) => {
+                println!("DHCP_RELEASE FROM: {}", from);
+                if let Some(pool) = pool.as_mut() {
+                    pool.release(&lease::client_id(&dhcp), Ipv4Addr::from(dhcp.body.ciaddr));
+                }
+                None
+            },
+            Some(MessageType::Decline) => {
+                println!("DHCP_DECLINE FROM: {}", from);
+                if let Some(pool) = pool.as_mut() {
+                    pool.decline(&lease::client_id(&dhcp), requested_ip(&dhcp));
+                }
                 None
             },
             _ => {
@@ -76,11 +162,10 @@ fn main() -> std::io::Result<()> {
         // If managed to create response, try to broadcast it.
         match res {
             Some(res) => {
-                let res = res.swap_endianess();
                 let bytes = res.as_bytes();
 
                 // check
-                let _ = socket.send_to(bytes.as_slice(), &broadcast);
+                let _ = socket.send_to(bytes.as_slice(), broadcast);
                 println!("Response sent");
             },
             _ => {
@@ -88,42 +173,431 @@ fn main() -> std::io::Result<()> {
             }
         }
     }
+
+    println!("Shutting down...");
+    for server in servers {
+        let _ = server.join();
+    }
+    Ok(())
+}
+
+// Server setup given as 'x.x.x.x:pp (x.x.x.x-y.y.y.y [lease_secs] | --proxy)' and flags.
+fn args_config(mut argv: Vec<String>) -> io::Result<Config> {
+    // Boot files per client architecture, given as '--bootfile arch=file'.
+    let mut boot = BootFiles::default();
+    while let Some(assignment) = take_flag(&mut argv, "--bootfile") {
+        boot = boot.assign(&assignment)
+            .ok_or({
+                let msg = format!("Invalid boot file '{}'. Example: --bootfile efi-x64=syslinux.efi", assignment);
+                io::Error::new(ErrorKind::InvalidInput, msg)
+            })?;
+    }
+    // Script for clients which already chainloaded into iPXE.
+    if let Some(script) = take_flag(&mut argv, "--ipxe-script") {
+        boot = boot.set_ipxe_script(script);
+    }
+    // Serve boot files over TFTP ourselves, from given directory.
+    let tftp_root = take_flag(&mut argv, "--tftp-root").map(PathBuf::from);
+    // Journal for the pool leases.
+    let lease_file = take_flag(&mut argv, "--lease-file").map(PathBuf::from);
+
+    // Get server address
+    let addr = argv.get(1)
+        .and_then(|addr| addr.parse::<SocketAddrV4>().ok())
+        .ok_or({
+            let msg = format!("You must specify address and port to listen on. Example: {0} x.x.x.x:pp | {0} --config FILE",
+                              argv.first().unwrap());
+            io::Error::new(ErrorKind::InvalidInput, msg)
+        })?;
+
+    // Get address pool. Without one, we act as proxyDHCP next to existing DHCP server.
+    let pool = match argv.get(2).map(String::as_str) {
+        Some("--proxy") => None,
+        range => {
+            let (first, last) = range
+                .and_then(parse_range)
+                .ok_or({
+                    let msg = format!("You must specify address pool or proxy mode. Example: {0} x.x.x.x:pp x.x.x.x-y.y.y.y [lease_secs] | {0} x.x.x.x:pp --proxy",
+                                      argv.first().unwrap());
+                    io::Error::new(ErrorKind::InvalidInput, msg)
+                })?;
+            let lease_time = argv.get(3)
+                .map_or(Some(DEFAULT_LEASE_TIME), |secs| secs.parse::<u64>().ok())
+                .and_then(lease_time)
+                .ok_or_else(|| {
+                    let msg = format!("Invalid lease time '{}', expected 1 to {} seconds.", argv[3], u32::MAX);
+                    io::Error::new(ErrorKind::InvalidInput, msg)
+                })?;
+            Some(LeasePool::new(first, last, lease_time))
+        }
+    };
+
+    let tftp = tftp_root.map(|root| TFTPServerConfig {
+        root,
+        addr: SocketAddrV4::new(*addr.ip(), TFTP_PORT),
+        ..Default::default()
+    });
+
+    Ok(Config {
+        listen: addr,
+        pool,
+        lease_file,
+        boot,
+        tftp,
+        hosts: Vec::new(),
+        pxelinux_template: None,
+        pxelinux_default: None
+    })
 }
 
-fn discover(addr: &SocketAddrV4, dhcp: DHCPDgram) -> Option<DHCPDgram> {
-    let copy_string = |string: &str, target: &mut [u8]| {
-        let zipped = target.into_iter().zip(string.as_bytes().iter());
-        for (place, data) in zipped {
-            *place = *data
+// Reserved host gets its address, everyone else one from the pool.
+fn discover(addr: &SocketAddrV4, dhcp: DHCPDgram, pool: &mut LeasePool, boot: &BootFiles, hosts: &Reservations) -> Option<DHCPDgram> {
+    let host = hosts.find(&dhcp);
+    // Nothing to boot, so don't hold an address for the client either.
+    let mut body = dhcp.body;
+    let class = set_bootfile(addr, &mut body, &dhcp, boot, host)?;
+    let offered = match host.and_then(|host| host.addr) {
+        Some(fixed) => fixed,
+        None => {
+            let requested = dhcp.option(DHCPOption::REQUESTED_IP).map(|_| requested_ip(&dhcp));
+            pool.offer(&lease::client_id(&dhcp), requested)?
         }
     };
 
+    body.op = BOOT_REPLY;
+    body.yiaddr = offered.octets();
+
+    pxe_reply(addr, &dhcp, body, class, pxe_options(addr, boot, None), host, Some(pool))
+}
+
+fn request(addr: &SocketAddrV4, dhcp: DHCPDgram, pool: &mut LeasePool, boot: &BootFiles, hosts: &Reservations) -> Option<DHCPDgram> {
+    let host = hosts.find(&dhcp);
+    let client = lease::client_id(&dhcp);
+    let requested = requested_ip(&dhcp);
+    let selecting = dhcp.option(DHCPOption::SERVER_ID).is_some();
+
+    // Client selected some other server, so offer made by us is void.
+    if let Some(server_id) = dhcp.option(DHCPOption::SERVER_ID) {
+        if *server_id != DHCPOption::ServerId(*addr.ip()) {
+            pool.cancel(&client);
+            return None;
+        }
+    }
+
     let mut body = dhcp.body;
     body.op = BOOT_REPLY;
-    copy_string("PXEServer", &mut body.sname);
-    copy_string("pxelinux.0", &mut body.filename);
 
-    let pxe = PXEBuilder::default()
-        .start(false)
-        .boot_servers(vec![addr.ip()])
+    match host.and_then(|host| host.addr) {
+        // Reserved host can't have any other address.
+        Some(fixed) => if requested != fixed {
+            return nak(addr, body);
+        },
+        // Client in SELECTING state may only take what we offered.
+        None if selecting && pool.offered(&client) != Some(requested) => return nak(addr, body),
+        // Rebooting client we have no record of is somebody else's (RFC 2131 4.3.2).
+        None if !selecting && pool.offered(&client).is_none() => return None,
+        None => if pool.request(&client, requested).is_none() {
+            // Without server id we are not the one to judge addresses outside our pool.
+            if !selecting && !pool.contains(requested) {
+                return None;
+            }
+            return nak(addr, body);
+        }
+    }
+
+    // Nothing to boot, so don't hold the address for a client we won't answer.
+    let class = match set_bootfile(addr, &mut body, &dhcp, boot, host) {
+        Some(class) => class,
+        None => {
+            pool.cancel(&client);
+            return None;
+        }
+    };
+    body.yiaddr = requested.octets();
+
+    pxe_reply(addr, &dhcp, body, class, pxe_options(addr, boot, None), host, Some(pool))
+}
+
+// ProxyDHCP OFFER. Only PXE clients are answered and no address is assigned.
+fn proxy_discover(addr: &SocketAddrV4, dhcp: DHCPDgram, boot: &BootFiles, hosts: &Reservations) -> Option<DHCPDgram> {
+    if !is_pxe_client(&dhcp) {
+        return None;
+    }
+
+    let host = hosts.find(&dhcp);
+    let mut body = dhcp.body;
+    body.op = BOOT_REPLY;
+    body.yiaddr = [0; 4];
+    let class = set_bootfile(addr, &mut body, &dhcp, boot, host)?;
+
+    pxe_reply(addr, &dhcp, body, class, pxe_options(addr, boot, None), None, None)
+}
+
+// Boot server ACK sent from port 4011 in reply to REQUEST following proxyDHCP OFFER,
+// or to client which picked an item from the boot menu.
+fn boot_ack(addr: &SocketAddrV4, dhcp: DHCPDgram, boot: &BootFiles, hosts: &Reservations) -> Option<DHCPDgram> {
+    if dhcp.message_type() != Some(MessageType::Request) || !is_pxe_client(&dhcp) {
+        return None;
+    }
+
+    let host = hosts.find(&dhcp);
+    let mut body = dhcp.body;
+    body.op = BOOT_REPLY;
+    body.yiaddr = [0; 4];
+    let class = set_bootfile(addr, &mut body, &dhcp, boot, host)?;
+
+    pxe_reply(addr, &dhcp, body, class, pxe_options(addr, boot, boot::boot_item(&dhcp)), None, None)
+}
+
+// Boot server loop. Client has an address by now, so answer directly.
+fn serve_boot(addr: &SocketAddrV4, socket: &UdpSocket, boot: &BootFiles, hosts: &Reservations, running: &AtomicBool) {
+    while let Some((dhcp, from)) = listen(socket, running) {
+        if dhcp.body.op != BOOT_REQUEST {
+            continue;
+        }
+
+        println!("PXE_BOOT_REQUEST FROM: {}", from);
+        if let Some(res) = boot_ack(addr, dhcp, boot, hosts) {
+            let bytes = res.as_bytes();
+            let _ = socket.send_to(bytes.as_slice(), from);
+            println!("Boot server response sent");
+        }
+    }
+}
+
+// Fill 'siaddr', 'sname' and 'filename' according to host reservation, client
+// architecture, menu item or iPXE script. Returns vendor class the reply should carry,
+// None when client is not to be answered at all.
+fn set_bootfile(addr: &SocketAddrV4, body: &mut DHCPBody, dhcp: &DHCPDgram,
+                boot: &BootFiles, host: Option<&Reservation>) -> Option<&'static [u8]> {
+    let file = boot.select(dhcp, host.and_then(|host| host.bootfile.as_deref()))?;
+
+    // Clients fetch the boot file from 'next server'.
+    body.siaddr = boot.next_server(*addr.ip()).octets();
+    copy_string(boot.server_name(), &mut body.sname);
+    copy_string(file, &mut body.filename);
+
+    match ClientArch::of(dhcp) {
+        Some(arch) if arch.is_http() => Some(b"HTTPClient"),
+        _ => Some(b"PXEClient")
+    }
+}
+
+// Option 60 starting with 'PXEClient'.
+fn is_pxe_client(dhcp: &DHCPDgram) -> bool {
+    match dhcp.option(DHCPOption::VENDOR_CLASS) {
+        Some(DHCPOption::VendorClass(class)) => class.starts_with(b"PXEClient"),
+        _ => false
+    }
+}
+
+// Address client asks for. Option 50 in SELECTING/INIT-REBOOT, 'ciaddr' otherwise.
+fn requested_ip(dhcp: &DHCPDgram) -> Ipv4Addr {
+    match dhcp.option(DHCPOption::REQUESTED_IP) {
+        Some(DHCPOption::RequestedIp(addr)) => *addr,
+        _ => Ipv4Addr::from(dhcp.body.ciaddr)
+    }
+}
+
+// Remove '--flag value' from arguments, returning the value.
+fn take_flag(argv: &mut Vec<String>, flag: &str) -> Option<String> {
+    let idx = argv.iter().position(|arg| arg == flag)?;
+    let value = argv.get(idx + 1).cloned().unwrap_or_default();
+    argv.drain(idx..argv.len().min(idx + 2));
+    Some(value)
+}
+
+// Parse 'x.x.x.x-y.y.y.y' into pool boundaries.
+fn parse_range(range: &str) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    let mut parts = range.splitn(2, '-');
+    let first = parts.next()?.parse::<Ipv4Addr>().ok()?;
+    let last = parts.next()?.parse::<Ipv4Addr>().ok()?;
+
+    if u32::from(first) > u32::from(last) {
+        return None;
+    }
+    Some((first, last))
+}
+
+// Lease time in seconds, if it fits into 32 bit options 51, 58 and 59.
+fn lease_time(secs: u64) -> Option<Duration> {
+    if secs == 0 || secs > u32::MAX as u64 {
+        return None;
+    }
+    Some(Duration::from_secs(secs))
+}
+
+/*
+ * PXE vendor options (option 43). Without a menu client downloads the boot
+ * file right away. With one it shows the menu and asks the boot server for
+ * the picked item, which is echoed back in boot server's reply.
+ */
+fn pxe_options(addr: &SocketAddrV4, boot: &BootFiles, item: Option<u16>) -> Vec<u8> {
+    let server = boot.next_server(*addr.ip());
+
+    let builder = match boot.menu() {
+        Some(menu) => {
+            let types = menu.items.iter()
+                .map(|item| item.server_type)
+                .filter(|server_type| *server_type != 0)
+                .collect::<Vec<u16>>();
+            let items = menu.items.iter()
+                .map(|item| (item.server_type, item.description.as_str()))
+                .collect::<Vec<(u16, &str)>>();
+
+            PXEBuilder::default()
+                .start_menu()
+                .typed_boot_servers(&types, vec![&server])
+                .menu_items(items)
+                .menu_prompt(menu.timeout, &menu.prompt)
+        },
+        None => PXEBuilder::default()
+            .start(false)
+            .boot_servers(vec![&server])
+    };
+
+    match item {
+        Some(server_type) => builder.boot_item(server_type, 0),
+        None => builder
+    }.end().build()
+}
+
+/*
+ * Reply with PXE vendor options. Lease options and host name of reserved host
+ * are only present if we own the address. Options 66 and 67 repeat what's in
+ * 'siaddr' and 'filename', so they're only sent to clients asking for them.
+ */
+fn pxe_reply(addr: &SocketAddrV4, dhcp: &DHCPDgram, body: DHCPBody, class: &[u8],
+             pxe: Vec<u8>, host: Option<&Reservation>, pool: Option<&LeasePool>) -> Option<DHCPDgram> {
+    // OFFER answers DISCOVER, ACK everything else.
+    let msg_type = match dhcp.message_type() {
+        Some(MessageType::Discover) => MessageType::Offer,
+        _ => MessageType::Ack
+    };
+    let mut required = vec![
+        DHCPOption::MessageType(msg_type),
+        DHCPOption::ServerId(*addr.ip())
+    ];
+    let mut optional = Vec::new();
+
+    if let Some(pool) = pool {
+        required.push(DHCPOption::LeaseTime(secs(pool.lease_time())));
+        optional.push(DHCPOption::RenewalTime(secs(pool.renewal_time())));
+        optional.push(DHCPOption::RebindingTime(secs(pool.rebinding_time())));
+
+        if let Some(hostname) = host.and_then(|host| host.hostname.as_ref()) {
+            optional.push(DHCPOption::HostName(hostname.clone()));
+        }
+    }
+
+    required.push(DHCPOption::VendorClass(class.to_vec()));
+    optional.push(DHCPOption::VendorSpecific(pxe));
+    if dhcp.requested_options().contains(&DHCPOption::TFTP_SERVER_NAME) {
+        optional.push(DHCPOption::TFTPServerName(Ipv4Addr::from(body.siaddr).to_string()));
+    }
+    let bootfile = read_string(&body.filename);
+    if !bootfile.is_empty() && dhcp.requested_options().contains(&DHCPOption::BOOTFILE_NAME) {
+        optional.push(DHCPOption::BootfileName(bootfile));
+    }
+
+    reply_options(dhcp, required, optional).into_iter()
+        .fold(DHCPDgramBuilder::default().body(body), |builder, option| builder.option(option))
         .end()
-        .build();
+        .build()
+}
+
+/*
+ * Options of the reply to 'dhcp'. Required ones go first, the rest in order
+ * of client's parameter request list, with those it didn't ask for at the end.
+ * Options which would make the reply longer than client accepts are left out.
+ */
+fn reply_options(dhcp: &DHCPDgram, required: Vec<DHCPOption>, mut optional: Vec<DHCPOption>) -> Vec<DHCPOption> {
+    let requested = dhcp.requested_options();
+    // Stable sort, unrequested options keep their order.
+    optional.sort_by_key(|option| requested.iter()
+                         .position(|code| *code == option.code())
+                         .unwrap_or(usize::MAX));
+
+    // Fixed part and END byte.
+    let mut size = BODY_LEN + 1 + required.iter()
+        .map(|option| option.encode().len())
+        .sum::<usize>();
+    let mut options = required;
+
+    for option in optional {
+        let len = option.encode().len();
+        if size + len > dhcp.max_message_size() {
+            println!("Option {} doesn't fit the reply, skipping", option.code());
+            continue;
+        }
+        size += len;
+        options.push(option);
+    }
+    options
+}
+
+// NAK carries no address nor boot parameters.
+fn nak(addr: &SocketAddrV4, body: DHCPBody) -> Option<DHCPDgram> {
+    let body = DHCPBody {
+        yiaddr: [0; 4],
+        siaddr: [0; 4],
+        ciaddr: [0; 4],
+        sname: [0; 64],
+        filename: [0; 128],
+        ..body
+    };
 
     DHCPDgramBuilder::default()
         .body(body)
-        .option(MESSAGE_TYPE, &[OFFER])
-        .option(SERVER_ID, &addr.ip().octets())
-        .option(CLASS_ID, "PXEClient".as_bytes())
-        .option(VENDOR_OPTIONS, &pxe[..])
+        .option(DHCPOption::MessageType(MessageType::Nak))
+        .option(DHCPOption::ServerId(*addr.ip()))
         .end()
         .build()
 }
 
-// Wait until DHCPDgram received
-fn listen(socket: &UdpSocket) -> (DHCPDgram, SocketAddrV4) {
+// Leases in the journal, one per line: address, state, client and seconds left.
+fn show_leases(path: &Path) -> io::Result<()> {
+    let now = SystemTime::now();
+    for record in journal::load(path)? {
+        let left = match record.expires.duration_since(now) {
+            Ok(left) => left.as_secs().to_string(),
+            Err(_) => "expired".to_string()
+        };
+        let client = match record.client.is_empty() {
+            true => "-".to_string(),
+            false => record.client.iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<String>>()
+                .join(":")
+        };
+        println!("{} {:?} {} {}", record.addr, record.state, client, left);
+    }
+    Ok(())
+}
+
+fn secs(duration: Duration) -> u32 {
+    duration.as_secs() as u32
+}
+
+// Zero terminated string from 'sname' or 'filename'.
+fn read_string(source: &[u8]) -> String {
+    let len = source.iter().position(|byte| *byte == 0).unwrap_or(source.len());
+    String::from_utf8_lossy(&source[..len]).into_owned()
+}
+
+fn copy_string(string: &str, target: &mut [u8]) {
+    let zipped = target.iter_mut().zip(string.as_bytes().iter());
+    for (place, data) in zipped {
+        *place = *data
+    }
+}
+
+// Wait until DHCPDgram received. None once server is shutting down,
+// socket needs read timeout for that to be noticed.
+fn listen(socket: &UdpSocket, running: &AtomicBool) -> Option<(DHCPDgram, SocketAddrV4)> {
     let mut buf = [0; 1<<12];
 
-    loop {
+    while running.load(Ordering::SeqCst) {
         let maybe_dhcp = socket.recv_from(&mut buf)
             // Convert SockAddr to SocketAddrV4
             .and_then(|(amt, from)| {
@@ -135,12 +609,11 @@ fn listen(socket: &UdpSocket) -> (DHCPDgram, SocketAddrV4) {
             })
             // Convert bytes to DHCP datagram
             .and_then(|(amt, ipv4)| {
-                let err = io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Unable to interpret as DHCP datagram."
-                );
                 DHCPDgram::from_bytes(&buf[..amt])
-                    .ok_or(err)
+                    .map_err(|err| {
+                        println!("Unable to interpret as DHCP datagram from {}: {}", ipv4, err);
+                        io::Error::new(ErrorKind::InvalidData, err)
+                    })
                     .map(|dhcp| (dhcp, ipv4))
             })
             // Convert to Option<T>
@@ -148,7 +621,248 @@ fn listen(socket: &UdpSocket) -> (DHCPDgram, SocketAddrV4) {
 
         // If failed, keep listening.
         if maybe_dhcp.is_some() {
-            return maybe_dhcp.unwrap();
+            return maybe_dhcp;
         }
     }
+    None
+}
+
+// Pool the tests hand out addresses from, 10.0.0.50-10.0.0.60 with 10 minute leases.
+#[cfg(test)]
+fn test_pool() -> LeasePool {
+    LeasePool::new(Ipv4Addr::new(10, 0, 0, 50), Ipv4Addr::new(10, 0, 0, 60), Duration::from_secs(600))
+}
+
+// Datagram with given body and options, as client would send it.
+#[cfg(test)]
+fn test_dgram(body: DHCPBody, options: Vec<DHCPOption>) -> DHCPDgram {
+    options.into_iter()
+        .fold(DHCPDgramBuilder::default().body(body), |builder, option| builder.option(option))
+        .end()
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn request_test() {
+    let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 67);
+    let mut pool = test_pool();
+    let boot = BootFiles::default();
+    let hosts = Reservations::new();
+
+    let mut body = DHCPBody { op: BOOT_REQUEST, hlen: 6, ..Default::default() };
+    body.chaddr[..6].copy_from_slice(&[1, 2, 3, 4, 5, 6]);
+
+    let mk_request = |msg_type: MessageType, requested: [u8; 4], server: [u8; 4]| test_dgram(body, vec![
+        DHCPOption::MessageType(msg_type),
+        DHCPOption::RequestedIp(Ipv4Addr::from(requested)),
+        DHCPOption::ServerId(Ipv4Addr::from(server))
+    ]);
+
+    let offer = discover(&addr, mk_request(MessageType::Discover, [0; 4], [0; 4]), &mut pool, &boot, &hosts).unwrap();
+    assert_eq!(offer.body.yiaddr, [10, 0, 0, 50]);
+    assert_eq!(offer.option(DHCPOption::LEASE_TIME), Some(&DHCPOption::LeaseTime(600)));
+
+    // Free address, but not the one we offered.
+    let nak = request(&addr, mk_request(MessageType::Request, [10, 0, 0, 51], [10, 0, 0, 1]), &mut pool, &boot, &hosts).unwrap();
+    assert_eq!(nak.message_type(), Some(MessageType::Nak));
+
+    let ack = request(&addr, mk_request(MessageType::Request, [10, 0, 0, 50], [10, 0, 0, 1]), &mut pool, &boot, &hosts).unwrap();
+    assert_eq!(ack.message_type(), Some(MessageType::Ack));
+    assert_eq!(ack.body.yiaddr, [10, 0, 0, 50]);
+
+    let nak = request(&addr, mk_request(MessageType::Request, [10, 0, 0, 70], [10, 0, 0, 1]), &mut pool, &boot, &hosts).unwrap();
+    assert_eq!(nak.message_type(), Some(MessageType::Nak));
+    assert_eq!(nak.body.yiaddr, [0; 4]);
+
+    // Rebooting client keeps its lease.
+    let reboot = |body: DHCPBody, requested: [u8; 4]| test_dgram(body, vec![
+        DHCPOption::MessageType(MessageType::Request),
+        DHCPOption::RequestedIp(Ipv4Addr::from(requested))
+    ]);
+    let ack = request(&addr, reboot(body, [10, 0, 0, 50]), &mut pool, &boot, &hosts).unwrap();
+    assert_eq!(ack.message_type(), Some(MessageType::Ack));
+
+    // Rebooting client we know nothing about is left alone, not given what it asked for.
+    let mut stranger = body;
+    stranger.chaddr[5] = 7;
+    assert!(request(&addr, reboot(stranger, [10, 0, 0, 52]), &mut pool, &boot, &hosts).is_none());
+    assert!(pool.lease(Ipv4Addr::new(10, 0, 0, 52)).is_none());
+
+    // Other server selected, stay silent and free the address.
+    assert!(request(&addr, mk_request(MessageType::Request, [10, 0, 0, 50], [10, 0, 0, 2]), &mut pool, &boot, &hosts).is_none());
+    assert_eq!(pool.leases().count(), 0);
+}
+
+#[test]
+fn proxy_test() {
+    use boot::{BootMenu, MenuItem};
+
+    let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 67);
+    let boot = BootFiles::default();
+    let hosts = Reservations::new();
+    let body = DHCPBody { op: BOOT_REQUEST, hlen: 6, ..Default::default() };
+
+    let mk_dgram = |msg_type: MessageType, class: &[u8]| test_dgram(body, vec![
+        DHCPOption::MessageType(msg_type),
+        DHCPOption::VendorClass(class.to_vec())
+    ]);
+
+    // Non-PXE clients are left for the real DHCP server.
+    assert!(proxy_discover(&addr, mk_dgram(MessageType::Discover, b"MSFT 5.0"), &boot, &hosts).is_none());
+
+    let offer = proxy_discover(&addr, mk_dgram(MessageType::Discover, b"PXEClient:Arch:00000:UNDI:002001"), &boot, &hosts).unwrap();
+    assert_eq!(offer.body.yiaddr, [0; 4]);
+    assert!(offer.option(DHCPOption::LEASE_TIME).is_none());
+    assert!(offer.option(DHCPOption::VENDOR_SPECIFIC).is_some());
+
+    assert!(boot_ack(&addr, mk_dgram(MessageType::Discover, b"PXEClient"), &boot, &hosts).is_none());
+    let ack = boot_ack(&addr, mk_dgram(MessageType::Request, b"PXEClient"), &boot, &hosts).unwrap();
+    assert_eq!(ack.message_type(), Some(MessageType::Ack));
+    assert_eq!(&ack.body.filename[..10], b"pxelinux.0");
+
+    // Item picked from the menu is answered with its file and echoed back.
+    let boot = boot.set_menu(BootMenu {
+        prompt: "Press F8".to_string(),
+        timeout: 5,
+        items: vec![MenuItem { server_type: 0x8001, description: "Install".to_string(), bootfile: Some("install.0".to_string()) }]
+    });
+    let mut request = mk_dgram(MessageType::Request, b"PXEClient");
+    request.options.insert(0, DHCPOption::VendorSpecific(vec![71, 4, 0x80, 0x01, 0, 0, 255]));
+    let ack = boot_ack(&addr, request, &boot, &hosts).unwrap();
+    assert_eq!(&ack.body.filename[..10], b"install.0\0");
+    match ack.option(DHCPOption::VENDOR_SPECIFIC) {
+        Some(DHCPOption::VendorSpecific(pxe)) => assert!(pxe.windows(6).any(|option| option == [71, 4, 0x80, 0x01, 0, 0])),
+        _ => panic!("No PXE options.")
+    }
+}
+
+#[test]
+fn bootfile_test() {
+    let boot = BootFiles::default()
+        .assign("http-x64=http://10.0.0.1/bootx64.efi").unwrap();
+
+    let mk_dgram = |arch: u16| test_dgram(DHCPBody::default(), vec![DHCPOption::ClientArch(vec![arch])]);
+
+    let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 67);
+    let mut body = DHCPBody::default();
+    assert_eq!(set_bootfile(&addr, &mut body, &mk_dgram(9), &boot, None), Some(&b"PXEClient"[..]));
+    assert_eq!(&body.filename[..19], b"efi64/syslinux.efi\0");
+    assert_eq!(&body.sname[..10], b"PXEServer\0");
+    assert_eq!(body.siaddr, [10, 0, 0, 1]);
+
+    let mut body = DHCPBody::default();
+    assert_eq!(set_bootfile(&addr, &mut body, &mk_dgram(16), &boot, None), Some(&b"HTTPClient"[..]));
+    assert_eq!(&body.filename[..29], b"http://10.0.0.1/bootx64.efi\0\0");
+
+    // HTTP client without URL for its arch is not answered.
+    let mut body = DHCPBody::default();
+    assert_eq!(set_bootfile(&addr, &mut body, &mk_dgram(19), &boot, None), None);
+    assert_eq!(body.filename, [0; 128]);
+    // There is no built-in arm64 loader, it takes whatever default is configured.
+    let mut body = DHCPBody::default();
+    assert_eq!(set_bootfile(&addr, &mut body, &mk_dgram(11), &boot, None), Some(&b"PXEClient"[..]));
+    assert_eq!(&body.filename[..11], b"pxelinux.0\0");
+
+    let mut pool = test_pool();
+    let discover_http = |pool: &mut LeasePool| {
+        let mut dhcp = mk_dgram(19);
+        dhcp.options.insert(0, DHCPOption::MessageType(MessageType::Discover));
+        discover(&addr, dhcp, pool, &boot, &Reservations::new())
+    };
+    assert!(discover_http(&mut pool).is_none());
+    assert!(pool.offered(&lease::client_id(&mk_dgram(19))).is_none());
+
+    // Nor is its request, though a wrong address still gets NAK.
+    let request_http = |pool: &mut LeasePool, requested: Ipv4Addr| {
+        let mut dhcp = mk_dgram(19);
+        dhcp.options.insert(0, DHCPOption::MessageType(MessageType::Request));
+        dhcp.options.insert(1, DHCPOption::RequestedIp(requested));
+        dhcp.options.insert(2, DHCPOption::ServerId(*addr.ip()));
+        request(&addr, dhcp, pool, &boot, &Reservations::new())
+    };
+    let client = lease::client_id(&mk_dgram(19));
+    let offered = pool.offer(&client, None).unwrap();
+    let nak = request_http(&mut pool, Ipv4Addr::new(10, 0, 0, 60)).unwrap();
+    assert_eq!(nak.message_type(), Some(MessageType::Nak));
+    assert!(request_http(&mut pool, offered).is_none());
+    assert!(pool.offered(&client).is_none());
+    assert_eq!(pool.leases().count(), 0);
+}
+
+#[test]
+fn reservation_test() {
+    let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 67);
+    let mut pool = test_pool();
+    let boot = BootFiles::default();
+    let hosts = Reservations::new()
+        .host(Reservation {
+            mac: Some([1, 2, 3, 4, 5, 6]),
+            addr: Some(Ipv4Addr::new(10, 0, 0, 5)),
+            hostname: Some("node1".to_string()),
+            bootfile: Some("node1.0".to_string()),
+            ..Default::default()
+        });
+
+    let mut body = DHCPBody { op: BOOT_REQUEST, hlen: 6, ..Default::default() };
+    body.chaddr[..6].copy_from_slice(&[1, 2, 3, 4, 5, 6]);
+    let mk_request = |msg_type: MessageType, requested: [u8; 4]| test_dgram(body, vec![
+        DHCPOption::MessageType(msg_type),
+        DHCPOption::RequestedIp(Ipv4Addr::from(requested))
+    ]);
+
+    // Fixed address, even outside the pool, and nothing taken from the pool.
+    let offer = discover(&addr, mk_request(MessageType::Discover, [10, 0, 0, 50]), &mut pool, &boot, &hosts).unwrap();
+    assert_eq!(offer.body.yiaddr, [10, 0, 0, 5]);
+    assert_eq!(&offer.body.filename[..8], b"node1.0\0");
+    assert_eq!(offer.option(DHCPOption::HOST_NAME), Some(&DHCPOption::HostName("node1".to_string())));
+    assert_eq!(pool.leases().count(), 0);
+
+    let ack = request(&addr, mk_request(MessageType::Request, [10, 0, 0, 5]), &mut pool, &boot, &hosts).unwrap();
+    assert_eq!(ack.message_type(), Some(MessageType::Ack));
+    assert_eq!(ack.body.yiaddr, [10, 0, 0, 5]);
+
+    let nak = request(&addr, mk_request(MessageType::Request, [10, 0, 0, 50]), &mut pool, &boot, &hosts).unwrap();
+    assert_eq!(nak.message_type(), Some(MessageType::Nak));
+}
+
+#[test]
+fn requested_options_test() {
+    use boot::{BootMenu, MenuItem};
+
+    let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 67);
+    let hosts = Reservations::new();
+    let body = DHCPBody { op: BOOT_REQUEST, hlen: 6, ..Default::default() };
+
+    let mk_discover = |options: Vec<DHCPOption>| test_dgram(body, [
+        vec![DHCPOption::MessageType(MessageType::Discover), DHCPOption::VendorClass(b"PXEClient".to_vec())],
+        options
+    ].concat());
+    let codes = |reply: DHCPDgram| reply.options.iter()
+        .map(|option| option.code())
+        .collect::<Vec<u8>>();
+
+    // Nothing asked for, everything we'd send anyway.
+    let boot = BootFiles::default();
+    let offer = discover(&addr, mk_discover(vec![]), &mut test_pool(), &boot, &hosts).unwrap();
+    assert_eq!(codes(offer), vec![53, 54, 51, 60, 58, 59, 43, 255]);
+
+    // Requested ones in client's order, boot file name included.
+    let offer = discover(&addr, mk_discover(vec![DHCPOption::ParameterRequestList(vec![67, 43, 1, 59, 66])]),
+                         &mut test_pool(), &boot, &hosts).unwrap();
+    assert_eq!(codes(offer.clone()), vec![53, 54, 51, 60, 67, 43, 59, 66, 58, 255]);
+    assert_eq!(offer.option(DHCPOption::BOOTFILE_NAME), Some(&DHCPOption::BootfileName("pxelinux.0".to_string())));
+    assert_eq!(offer.option(DHCPOption::TFTP_SERVER_NAME), Some(&DHCPOption::TFTPServerName("10.0.0.1".to_string())));
+
+    // Long menu doesn't fit into 576 bytes, unless client says it takes more.
+    let items = (0..10)
+        .map(|idx| MenuItem { server_type: 0x8000 + idx, description: format!("Boot item number {:>14}", idx), bootfile: None })
+        .collect();
+    let boot = boot.set_menu(BootMenu { prompt: "Press F8".to_string(), timeout: 5, items });
+    let offer = discover(&addr, mk_discover(vec![]), &mut test_pool(), &boot, &hosts).unwrap();
+    assert!(offer.option(DHCPOption::VENDOR_SPECIFIC).is_none());
+    assert!(offer.clone().as_bytes().len() <= 548);
+
+    let offer = discover(&addr, mk_discover(vec![DHCPOption::MaxMessageSize(1500)]), &mut test_pool(), &boot, &hosts).unwrap();
+    assert!(offer.option(DHCPOption::VENDOR_SPECIFIC).is_some());
 }
@@ -1,57 +1,271 @@
 use std::io;
-use std::io::Read;
+use std::io::{BufReader, Read};
 
-use std::fs;
-use std::fs::File;
 use std::path::{
+    Component,
+    Path,
     PathBuf
 };
 
-use std::num::Wrapping;
+use std::time::{Duration, Instant};
 
 use std::net::{
+    Ipv4Addr,
     UdpSocket,
     SocketAddr,
     SocketAddrV4
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+mod netascii;
+mod provider;
+mod pxelinux;
+mod upload;
+use netascii::NetasciiReader;
+pub use provider::{FileProvider, FsProvider, GeneratedProvider, MemoryProvider};
+pub use pxelinux::{PxeHost, PxelinuxProvider, DEFAULT_TEMPLATE};
+pub use upload::OverwritePolicy;
+use upload::TFTPUpload;
+
+// Block size unless client negotiates another one (RFC 1350). Not configurable:
+// client which didn't send blksize expects exactly this and nothing else.
+const DEFAULT_BLKSIZE: u16 = 512;
+
+// Block sizes allowed by RFC 2348.
+pub const MIN_BLKSIZE: u16 = 8;
+pub const MAX_BLKSIZE: u16 = 65464;
+
+#[derive(Clone, Debug)]
+pub struct TFTPServerConfig {
+    // Directory files are served from.
+    pub root: PathBuf,
+    pub addr: SocketAddrV4,
+    // Upper bound for negotiated block size. Without negotiation it's always 512.
+    pub max_blksize: u16,
+    // Upper bound for negotiated window size (RFC 7440).
+    pub max_windowsize: u16,
+    // Wait for ACK before resending last packet.
+    pub timeout: Duration,
+    // Resends before transfer is abandoned.
+    pub retries: u8,
+    // Transfers served at the same time, each one in its own thread.
+    pub max_transfers: usize,
+    // Block number following 65535 in files too big for 16 bit counter.
+    pub block_rollover: BlockRollover,
+    // Directory WRQ writes into. Uploads are refused unless set.
+    pub upload_dir: Option<PathBuf>,
+    // What to do when uploaded file already exists.
+    pub overwrite: OverwritePolicy,
+    // Uploads growing past this many bytes are aborted.
+    pub max_upload_size: u64
+}
+
+/*
+ * RFC 1350 doesn't say what comes after block 65535. Most clients (PXELINUX,
+ * iPXE, tftp-hpa, U-Boot) expect 0, some older ones 1.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockRollover {
+    Zero,
+    One
+}
+
+impl BlockRollover {
+    fn next(self, block: u16) -> u16 {
+        match (block, self) {
+            (u16::MAX, BlockRollover::Zero) => 0,
+            (u16::MAX, BlockRollover::One) => 1,
+            (block, _) => block + 1
+        }
+    }
+}
+
+impl Default for TFTPServerConfig {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("/srv/tftp"),
+            addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 69),
+            max_blksize: MAX_BLKSIZE,
+            max_windowsize: 64,
+            timeout: Duration::from_secs(5),
+            retries: 5,
+            max_transfers: 64,
+            block_rollover: BlockRollover::Zero,
+            upload_dir: None,
+            overwrite: OverwritePolicy::Refuse,
+            max_upload_size: 64 << 20
+        }
+    }
+}
+
+// Transfer mode from the request. 'mail' is obsolete and refused like any unknown one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Octet,
+    Netascii
+}
+
+impl Mode {
+    fn parse(mode: &str) -> io::Result<Self> {
+        match mode.to_lowercase().as_str() {
+            "octet" => Ok(Mode::Octet),
+            "netascii" => Ok(Mode::Netascii),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "Illegal TFTP operation."))
+        }
+    }
+}
+
+// Options negotiated with the client (RFC 2347). Only ones client asked for are set.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct TFTPOptions {
+    blksize: Option<u16>,
+    tsize: Option<u64>,
+    // Retransmission interval in seconds (RFC 2349).
+    timeout: Option<u8>,
+    windowsize: Option<u16>
+}
+
+impl TFTPOptions {
+    // Negotiated retransmission interval, server default otherwise.
+    fn timeout(&self, default: Duration) -> Duration {
+        self.timeout
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(default)
+    }
+}
 
 struct TFTPTransfer {
+    options: TFTPOptions,
+    // Number of last block read from file.
     block_cnt: u16,
     block_sz: u16,
+    // Blocks sent before waiting for ACK.
+    window_sz: u16,
+    rollover: BlockRollover,
     done: bool,
-    file: File
+    file: Box<dyn Read + Send>,
+    size: u64,
+    // OACK, kept for retransmission until client ACKs it.
+    last: Vec<u8>,
+    // DATA packets sent but not acknowledged yet with their numbers, oldest first.
+    unacked: VecDeque<(u16, Vec<u8>)>,
+    sent_at: Instant,
+    retries: u8
+}
+
+// What to do after ACK or timer event.
+#[derive(Debug, PartialEq)]
+enum Step {
+    // Packets to send, in order.
+    Send(Vec<Vec<u8>>),
+    // Duplicate ACK or timer not expired yet, nothing to send.
+    Wait,
+    // Transfer complete, forget it.
+    Finished,
+    // Out of retries. Send error and forget the transfer.
+    Abort(Vec<u8>)
 }
 
 /*
  * TODO:
- * - Security features.
  * - Better logging.
- * - Mult-threading
- * - Better error handling.
  */
 
-const ROOT_DIR: &'static str = "R:\\tftpboot";
-
 impl TFTPTransfer {
-    fn new(block_sz: u16, path_str: impl Into<String>) -> io::Result<Self> {
+    fn new(block_sz: u16, mode: Mode, files: &dyn FileProvider, filename: &str) -> io::Result<Self> {
+        let (fil, size) = files.open(filename)?;
+        let file: Box<dyn Read + Send> = match mode {
+            Mode::Octet => fil,
+            Mode::Netascii => Box::new(NetasciiReader::new(BufReader::new(fil)))
+        };
+        Ok(Self::from_reader(block_sz, file, size))
+    }
 
-        let path_str = path_str.into();
-        let path = PathBuf::from(&path_str);
+    fn from_reader(block_sz: u16, file: Box<dyn Read + Send>, size: u64) -> Self {
+        TFTPTransfer {
+            options: TFTPOptions::default(),
+            block_cnt: 0,
+            done: false,
+            block_sz,
+            window_sz: 1,
+            rollover: BlockRollover::Zero,
+            file,
+            size,
+            last: Vec::new(),
+            unacked: VecDeque::new(),
+            sent_at: Instant::now(),
+            retries: 0
+        }
+    }
 
-        let mut base = PathBuf::from(ROOT_DIR);
-        println!("PATH: {:?}", path);
-        base.push(PathBuf::from(path));
+    // OACK if client asked for any option, otherwise straight to DATA block 1.
+    fn start(&mut self) -> Step {
+        if self.options == TFTPOptions::default() {
+            return self.on_ack(0);
+        }
 
-        println!("FILPATH: {:?}", base);
+        let oack = TFTP::opt_ack(self.options.blksize, self.options.tsize, self.options.timeout, self.options.windowsize);
+        self.last = oack.clone();
+        self.sent();
+        Step::Send(vec![oack])
+    }
 
-        let fil = File::open(base)?;
-        Ok(TFTPTransfer {
-            block_cnt: 0,
-            done: false,
-            block_sz: block_sz,
-            file: fil
-        })
+    // Restart retransmission timer.
+    fn sent(&mut self) {
+        self.sent_at = Instant::now();
+        self.retries = 0;
+    }
+
+    /*
+     * ACK of any block in flight moves the window forward (RFC 7440). Blocks
+     * after it are resent together with new ones, client evidently lost them.
+     * ACK of nothing new is a duplicate and is ignored rather than answered,
+     * otherwise each delayed ACK would double the traffic (Sorcerer's
+     * Apprentice Syndrome).
+     */
+    fn on_ack(&mut self, block: u16) -> Step {
+        // Numbers repeat after rollover, so ACK is matched against blocks in flight.
+        let acked = match self.unacked.iter().position(|(number, _)| *number == block) {
+            Some(pos) => pos + 1,
+            // ACK of OACK, or of nothing when there were no options.
+            None if self.unacked.is_empty() && block == self.block_cnt => 0,
+            None => return Step::Wait
+        };
+
+        self.unacked.drain(..acked);
+        if self.done && self.unacked.is_empty() {
+            return Step::Finished;
+        }
+
+        while self.unacked.len() < self.window_sz as usize && !self.done {
+            match self.next_block() {
+                Some(bytes) => self.unacked.push_back((self.block_cnt, TFTP::data(self.block_cnt, bytes))),
+                None => return Step::Abort(TFTP::error(0, "Unable to read next block."))
+            }
+        }
+
+        self.sent();
+        Step::Send(self.unacked.iter().map(|(_, packet)| packet.clone()).collect())
+    }
+
+    fn on_tick(&mut self, now: Instant, timeout: Duration, max_retries: u8) -> Step {
+        if now.duration_since(self.sent_at) < timeout {
+            return Step::Wait;
+        }
+        if self.retries >= max_retries {
+            return Step::Abort(TFTP::error(0, "Transfer timed out."));
+        }
+
+        // Whole window goes again, starting after last acknowledged block.
+        self.retries += 1;
+        self.sent_at = now;
+        if self.unacked.is_empty() {
+            return Step::Send(vec![self.last.clone()]);
+        }
+        Step::Send(self.unacked.iter().map(|(_, packet)| packet.clone()).collect())
     }
 
     fn next_block(&mut self) -> Option<Vec<u8>> {
@@ -59,69 +273,278 @@ impl TFTPTransfer {
             return None;
         }
 
+        // Short read means end of file, so keep reading until block is full.
         let mut buff = vec![0; self.block_sz as usize];
-        let bytes_read = self.file.read(&mut buff)
-            .unwrap_or(0);
+        let mut len = 0;
+        while len < buff.len() {
+            match self.file.read(&mut buff[len..]) {
+                Ok(0) => break,
+                Ok(amt) => len += amt,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return None
+            }
+        }
+        buff.truncate(len);
+
+        self.block_cnt = self.rollover.next(self.block_cnt);
+        self.done = buff.len() != self.block_sz as usize;
+
+        Some(buff)
+    }
+
+    fn tsize(&self) -> u64 {
+        self.size
+    }
+}
+
+/*
+ * Single transfer, served from its own ephemeral port as RFC 1350 requires.
+ * The port number is our transfer ID, client's address and port is theirs.
+ */
+struct Session {
+    peer: SocketAddr,
+    socket: UdpSocket,
+    transfer: Transfer
+}
+
+// Session either sends file to the client (RRQ) or receives one (WRQ).
+enum Transfer {
+    Read(TFTPTransfer),
+    Write(TFTPUpload)
+}
+
+impl Transfer {
+    fn start(&mut self) -> Step {
+        match self {
+            Transfer::Read(transfer) => transfer.start(),
+            Transfer::Write(upload) => upload.start()
+        }
+    }
+
+    fn sent_at(&self) -> Instant {
+        match self {
+            Transfer::Read(transfer) => transfer.sent_at,
+            Transfer::Write(upload) => upload.sent_at
+        }
+    }
+
+    fn timeout(&self, default: Duration) -> Duration {
+        match self {
+            Transfer::Read(transfer) => transfer.options.timeout(default),
+            Transfer::Write(upload) => upload.options.timeout(default)
+        }
+    }
+
+    fn on_tick(&mut self, now: Instant, timeout: Duration, max_retries: u8) -> Step {
+        match self {
+            Transfer::Read(transfer) => transfer.on_tick(now, timeout, max_retries),
+            Transfer::Write(upload) => upload.on_tick(now, timeout, max_retries)
+        }
+    }
+}
+
+impl Session {
+    fn new(ip: Ipv4Addr, peer: SocketAddr, transfer: Transfer) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(ip, 0))?;
+        Ok(Self { peer, socket, transfer })
+    }
+
+    fn send(&self, packet: &[u8]) {
+        let _ = self.socket.send_to(packet, self.peer);
+    }
+
+    // Serve the transfer until it's finished or abandoned. Blocks, meant to have a thread of its own.
+    fn run(mut self, config: &TFTPServerConfig) {
+        // Big enough for DATA with largest block size.
+        let mut buf = vec![0; 65536];
+        loop {
+            // Sleep until next packet or retransmission, whichever comes first.
+            let wait = self.transfer.timeout(config.timeout)
+                .checked_sub(self.transfer.sent_at().elapsed())
+                .unwrap_or_default()
+                .max(Duration::from_millis(1));
+            let _ = self.socket.set_read_timeout(Some(wait));
 
-        self.block_cnt = (Wrapping(self.block_cnt) + Wrapping(1)).0;
-        self.done = bytes_read as u16 != self.block_sz;
+            let alive = match self.socket.recv_from(&mut buf) {
+                Ok((amt, from)) => {
+                    let step = self.on_packet(from, &buf[..amt]);
+                    self.apply(step)
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut => true,
+                Err(_) => false
+            };
 
-        // Ciebie trzeba skrócić troszeczkę
-        let buff_trim = &buff[..bytes_read];
-        Some(buff_trim.to_vec())
+            if !alive || !self.on_tick(Instant::now(), config) {
+                break;
+            }
+        }
     }
 
-    fn tsize(&self) -> usize {
-        self.file.metadata()
-            .map(|meta| meta.len() as usize)
-            .unwrap_or(0)
+    fn on_packet(&mut self, from: SocketAddr, bytes: &[u8]) -> Step {
+        // Stray packet must not disturb the transfer. Stray error isn't answered.
+        if from != self.peer {
+            if bytes.get(1) != Some(&TFTP::ERROR) {
+                let _ = self.socket.send_to(&TFTP::error(5, "Unknown transfer ID."), from);
+            }
+            return Step::Wait;
+        }
+
+        match (bytes.get(1), &mut self.transfer) {
+            (Some(&TFTP::ACK), Transfer::Read(transfer)) => TFTP::parse_ack(bytes)
+                .map(|block| transfer.on_ack(block))
+                .unwrap_or(Step::Wait),
+            (Some(&TFTP::DATA), Transfer::Write(upload)) => TFTP::parse_data(bytes)
+                .map(|(block, data)| upload.on_data(block, data))
+                .unwrap_or(Step::Wait),
+            // Client gave up.
+            (Some(&TFTP::ERROR), _) => Step::Finished,
+            _ => Step::Abort(TFTP::error(4, "Illegal TFTP operation."))
+        }
+    }
+
+    fn on_tick(&mut self, now: Instant, config: &TFTPServerConfig) -> bool {
+        let timeout = self.transfer.timeout(config.timeout);
+        let step = self.transfer.on_tick(now, timeout, config.retries);
+        if let Step::Abort(_) = step {
+            println!("Transfer to {} timed out.", self.peer);
+        }
+        self.apply(step)
+    }
+
+    // Returns false once session is over.
+    fn apply(&self, step: Step) -> bool {
+        match step {
+            Step::Send(packets) => {
+                for packet in packets {
+                    self.send(&packet);
+                }
+                true
+            },
+            Step::Wait => true,
+            Step::Finished => false,
+            Step::Abort(packet) => {
+                self.send(&packet);
+                false
+            }
+        }
     }
 }
 
 pub struct TFTPServer {
-    transfers: HashMap<SocketAddr, TFTPTransfer>
+    config: Arc<TFTPServerConfig>,
+    // Where read requests are served from. Files under the root by default.
+    files: Arc<dyn FileProvider>,
+    // Clients with transfer in progress.
+    active: Arc<Mutex<HashSet<SocketAddr>>>
 }
 
 impl TFTPServer {
-    pub fn new() -> Self {
+    pub fn new(config: TFTPServerConfig) -> Self {
         Self {
-            transfers: HashMap::new()
+            files: Arc::new(FsProvider::new(config.root.clone())),
+            config: Arc::new(config),
+            active: Arc::new(Mutex::new(HashSet::new()))
         }
     }
 
-    pub fn start(&mut self, addr: SocketAddrV4) -> io::Result<()> {
-        let socket = UdpSocket::bind(addr)?;
-        loop {
+    pub fn files(mut self, files: impl FileProvider + 'static) -> Self {
+        self.files = Arc::new(files);
+        self
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        let socket = UdpSocket::bind(self.config.addr)?;
+        self.serve(socket, &AtomicBool::new(true))
+    }
+
+    /*
+     * Serve requests arriving on already bound socket until 'running' is
+     * cleared. New requests are ignored from then on, while transfers in
+     * progress get to finish, or give up after their retries.
+     */
+    pub fn serve(&mut self, socket: UdpSocket, running: &AtomicBool) -> io::Result<()> {
+        let poll = Duration::from_millis(200);
+        socket.set_read_timeout(Some(poll))?;
+
+        if let Some(dir) = &self.config.upload_dir {
+            if let Err(err) = upload::remove_stale_parts(dir) {
+                println!("Unable to clean upload directory: {}", err);
+            }
+        }
+
+        while running.load(Ordering::SeqCst) {
             let _ = self.listen(&socket);
         }
+        while !self.active.lock().unwrap().is_empty() {
+            thread::sleep(poll);
+        }
+        Ok(())
     }
 
+    fn spawn(&self, session: Session) {
+        let config = self.config.clone();
+        let active = self.active.clone();
+
+        active.lock().unwrap().insert(session.peer);
+        thread::spawn(move || {
+            let peer = session.peer;
+            session.run(&config);
+            active.lock().unwrap().remove(&peer);
+        });
+    }
 
     fn listen(&mut self, socket: &UdpSocket) -> io::Result<()> {
         let mut buf = [0; 4096];
         let (amt, from) = socket.recv_from(&mut buf)?;
 
         let buf = &mut buf[..amt];
+        let (running, busy) = {
+            let active = self.active.lock().unwrap();
+            (active.contains(&from), active.len() >= self.config.max_transfers)
+        };
+
         let res = match buf.get(1) {
-            // Read Request
-            Some(&TFTP::RRQ) => {
-                // Parse request here
-                let res = TFTP::parse_rrq(&buf)
-                    .map_err(|err| {println!("{}", err); err})
-                    .map(|transfer| {
-                        let ack = TFTP::opt_ack(Some(transfer.block_sz), Some(transfer.tsize()));
-                        self.transfers.insert(from.clone(), transfer);
-                        ack
-                    })
-                    .unwrap_or(TFTP::error(1, "No such file."));
-                Ok(res)
+            // Retransmitted request, session already answers it.
+            Some(&TFTP::RRQ) | Some(&TFTP::WRQ) if running =>
+                return Ok(()),
+
+            Some(&TFTP::RRQ) | Some(&TFTP::WRQ) if busy =>
+                Ok(TFTP::error(0, "Too many transfers, try again later.")),
+
+            // Read or Write Request
+            Some(&TFTP::RRQ) | Some(&TFTP::WRQ) => {
+                let transfer = match buf[1] {
+                    TFTP::RRQ => TFTP::parse_rrq(buf, &self.config, &*self.files).map(Transfer::Read),
+                    _ => TFTP::parse_wrq(buf, &self.config).map(Transfer::Write)
+                };
+                let session = transfer
+                    .and_then(|transfer| Session::new(*self.config.addr.ip(), from, transfer));
+
+                match session {
+                    Ok(mut session) => {
+                        let step = session.transfer.start();
+                        if session.apply(step) {
+                            self.spawn(session);
+                        }
+                        return Ok(());
+                    },
+                    Err(err) => {
+                        println!("{}", err);
+                        Ok(TFTP::error_from(&err))
+                    }
+                }
             },
 
-            // Transfers next or returns error if transfer impossible.
+            // Transfer packets belong to session ports.
             Some(&TFTP::ACK) =>
-                self.send_next(&from),
+                Ok(TFTP::error(5, "Unknown transfer ID.")),
+
+            // Errors are never answered, not even stray ones.
+            Some(&TFTP::ERROR) =>
+                return Ok(()),
 
-            // Send error if something other than ACK or RRQ.
+            // Send error if something other than a request.
             Some(_) =>
                 Ok(TFTP::error(20, "Unsuported operation.")),
 
@@ -129,24 +552,9 @@ impl TFTPServer {
                 Err(io::Error::new(io::ErrorKind::InvalidData, "Not enough data."))
         }?;
 
-        socket.send_to(res.as_slice(), &from)?;
+        socket.send_to(res.as_slice(), from)?;
         Ok(())
     }
-
-    fn send_next(&mut self, to: &SocketAddr) -> io::Result<Vec<u8>> {
-        let is_done = self.transfers.get_mut(&to).map(|t| t.done).unwrap_or(false);
-        if is_done {
-            self.transfers.remove(to);
-            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "Transfer finished."));
-        }
-
-        Ok(
-            self.transfers.get_mut(&to)
-                .and_then(|transfer| transfer.next_block().map(|blk| (transfer, blk)))
-                .map(|(transfer, bytes)| TFTP::data(transfer.block_cnt, bytes))
-                .unwrap_or(TFTP::error(2, "Unable to read next block."))
-        )
-    }
 }
 
 struct TFTP {}
@@ -161,7 +569,7 @@ impl TFTP {
 
     // Into<String> should probably be replaced with CStr or IntoCStr trait.
     pub fn wrq(filname: impl Into<String>, mode: impl Into<String>) -> Vec<u8> {
-        vec![vec![Self::WRQ, 0x02],
+        vec![vec![0x00, Self::WRQ],
              str_to_bytes(filname.into()),
              str_to_bytes(mode.into())
         ].concat()
@@ -187,7 +595,7 @@ impl TFTP {
         vec![0x00, Self::ACK, hi, lo]
     }
 
-    pub fn opt_ack(blksize: Option<u16>, tsize: Option<usize>) -> Vec<u8> {
+    pub fn opt_ack(blksize: Option<u16>, tsize: Option<u64>, timeout: Option<u8>, windowsize: Option<u16>) -> Vec<u8> {
         let option_to_bytes = |name: &str, option: Option<String>| {
             option
                 .map(|num| vec![str_to_bytes(name), str_to_bytes(num)].concat())
@@ -196,8 +604,10 @@ impl TFTP {
 
         let blksize = option_to_bytes("blksize", blksize.map(|sz| sz.to_string()));
         let tsize = option_to_bytes("tsize", tsize.map(|sz| sz.to_string()));
+        let timeout = option_to_bytes("timeout", timeout.map(|secs| secs.to_string()));
+        let windowsize = option_to_bytes("windowsize", windowsize.map(|sz| sz.to_string()));
 
-        vec![vec![0x00, Self::OPT_ACK], blksize, tsize].concat()
+        vec![vec![0x00, Self::OPT_ACK], blksize, tsize, timeout, windowsize].concat()
     }
 
     pub fn error(code: u16, msg: impl Into<String>) -> Vec<u8> {
@@ -207,48 +617,146 @@ impl TFTP {
         vec![vec![0x00, Self::ERROR, hi, lo], str_to_bytes(msg.into())].concat()
     }
 
-    pub fn parse_rrq(bytes: &[u8]) -> io::Result<TFTPTransfer> {
-        let opcode = bytes.get(0)
-            .and_then(|b1| bytes.get(1).map(|b2| (b1.clone(), b2.clone())))
-            .unwrap_or((0, 0));
+    // Map I/O failure onto TFTP error packet.
+    pub fn error_from(err: &io::Error) -> Vec<u8> {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => Self::error(2, "Access violation."),
+            io::ErrorKind::FileTooLarge
+                | io::ErrorKind::StorageFull => Self::error(3, "Disk full or allocation exceeded."),
+            io::ErrorKind::AlreadyExists => Self::error(6, "File already exists."),
+            io::ErrorKind::Unsupported => Self::error(4, "Illegal TFTP operation."),
+            _ => Self::error(1, "No such file.")
+        }
+    }
+
+    pub fn parse_data(bytes: &[u8]) -> io::Result<(u16, &[u8])> {
+        match bytes {
+            &[0x00, Self::DATA, hi, lo, ref data @ ..] => Ok((((hi as u16) << 8) | lo as u16, data)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed DATA."))
+        }
+    }
+
+    pub fn parse_ack(bytes: &[u8]) -> io::Result<u16> {
+        match bytes {
+            &[0x00, Self::ACK, hi, lo] => Ok(((hi as u16) << 8) | lo as u16),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed ACK."))
+        }
+    }
 
-        if opcode != (0, Self::RRQ) {
+    pub fn parse_rrq(bytes: &[u8], config: &TFTPServerConfig, files: &dyn FileProvider) -> io::Result<TFTPTransfer> {
+        let (filname, mode, options) = Self::parse_request(bytes, Self::RRQ)?;
+        let negotiated = Self::negotiate(&options, config);
+
+        let block_sz = negotiated.blksize.unwrap_or(DEFAULT_BLKSIZE);
+        let mut transfer = TFTPTransfer::new(block_sz, mode, files, &filname)?;
+        transfer.window_sz = negotiated.windowsize.unwrap_or(1);
+        transfer.rollover = config.block_rollover;
+        // Netascii grows the file by unknown amount, so its size is left unanswered.
+        transfer.options = TFTPOptions {
+            tsize: options.get("tsize").filter(|_| mode == Mode::Octet).map(|_| transfer.tsize()),
+            ..negotiated
+        };
+        Ok(transfer)
+    }
+
+    pub fn parse_wrq(bytes: &[u8], config: &TFTPServerConfig) -> io::Result<TFTPUpload> {
+        let dir = config.upload_dir.as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "Uploads are disabled."))?;
+        let (filname, mode, options) = Self::parse_request(bytes, Self::WRQ)?;
+
+        // Client announces size of the upload. Window size is only supported for reads.
+        let negotiated = TFTPOptions {
+            tsize: options.get("tsize").and_then(|string| string.parse::<u64>().ok()),
+            windowsize: None,
+            ..Self::negotiate(&options, config)
+        };
+        TFTPUpload::new(dir, &filname, mode, negotiated, config)
+    }
+
+    // Filename, mode and options of RRQ or WRQ.
+    fn parse_request(bytes: &[u8], opcode: u8) -> io::Result<(String, Mode, HashMap<String, String>)> {
+        if bytes.get(..2) != Some(&[0x00, opcode][..]) {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid opcode."));
         }
 
-        let rest = &bytes[2..].to_vec();
-        let chunks = rest
+        let chunks = bytes[2..]
             .split(|b| *b == 0x00)
             .collect::<Vec<&[u8]>>();
-        let mut windows = chunks
-            .windows(2);
 
-        if let Some(&[filname, _mode]) = windows.next() {
+        if let [filname, mode, options @ ..] = &chunks[..] {
             let filname_str = std::str::from_utf8(filname)
                 .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Unable to parse filename string."))?;
-            let _mode_str = std::str::from_utf8(filname)
+            let mode_str = std::str::from_utf8(mode)
                 .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Unable to parse mode string."))?;
+            let mode = Mode::parse(mode_str)?;
 
-            let options = windows.fold(HashMap::<String, String>::new(), |mut acc, window| {
-                let key   = window.get(0).and_then(|option_name| std::str::from_utf8(option_name).ok());
-                let value = window.get(1).and_then(|option_value| std::str::from_utf8(option_value).ok());
+            // Option names are case insensitive. Trailing empty chunk is left out by chunks_exact.
+            let options = options.chunks_exact(2).fold(HashMap::<String, String>::new(), |mut acc, pair| {
+                let key   = std::str::from_utf8(pair[0]).ok();
+                let value = std::str::from_utf8(pair[1]).ok();
 
                 if let (Some(key), Some(value)) = (key, value) {
-                    acc.insert(key.to_string(), value.to_string());
+                    acc.insert(key.to_lowercase(), value.to_string());
                 }
 
                 acc
             });
 
-            let blksize = options.get("blksize")
-                .and_then(|string| string.parse::<u16>().ok())
-                .unwrap_or(1488);
-
-            return TFTPTransfer::new(blksize, filname_str);
+            return Ok((filname_str.to_string(), mode, options));
         }
 
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing filename or mode."));
+        Err(io::Error::new(io::ErrorKind::InvalidData, "Missing filename or mode."))
+    }
+
+    // Options common to both directions, tsize is up to the caller.
+    fn negotiate(options: &HashMap<String, String>, config: &TFTPServerConfig) -> TFTPOptions {
+        let blksize = options.get("blksize")
+            .and_then(|string| string.parse::<u16>().ok())
+            .map(|blksize| blksize.clamp(MIN_BLKSIZE, MAX_BLKSIZE).min(config.max_blksize));
+        let windowsize = options.get("windowsize")
+            .and_then(|string| string.parse::<u16>().ok())
+            .map(|windowsize| windowsize.clamp(1, config.max_windowsize.max(1)));
+        // Out of range timeout is left unacknowledged, client falls back to its default.
+        let timeout = options.get("timeout")
+            .and_then(|string| string.parse::<u8>().ok())
+            .filter(|secs| *secs >= 1);
+
+        TFTPOptions { blksize, tsize: None, timeout, windowsize }
+    }
+}
+
+/*
+ * Resolve client supplied filename inside the root. Absolute paths and '..'
+ * are refused outright, symlinks are followed and must stay inside the root.
+ * Both '/' and '\\' are accepted as separators.
+ */
+fn resolve_path(root: &Path, filename: &str) -> io::Result<PathBuf> {
+    let relative = relative_path(filename)?;
+
+    let root = root.canonicalize()?;
+    let path = root.join(relative).canonicalize()?;
+    if !path.starts_with(&root) {
+        return Err(access_denied());
+    }
+
+    Ok(path)
+}
+
+// Client supplied filename as relative path, without any way out of the directory.
+fn relative_path(filename: &str) -> io::Result<PathBuf> {
+    let relative = PathBuf::from(filename.replace('\\', "/"));
+
+    let is_plain = relative.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if filename.is_empty() || !is_plain {
+        return Err(access_denied());
     }
+
+    Ok(relative)
+}
+
+fn access_denied() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Access violation.")
 }
 
 fn str_to_bytes(string: impl Into<String>) -> Vec<u8> {
@@ -292,3 +800,411 @@ fn error_test() {
     ];
     assert_eq!(TFTP::error(0, "TFTP Aborted"), bytes)
 }
+
+/*
+ * Small TFTP root with a file outside of it, in a temporary directory.
+ * Removed once dropped, also when the test fails halfway.
+ */
+#[cfg(test)]
+struct ScratchDir(PathBuf);
+
+#[cfg(test)]
+impl ScratchDir {
+    // Default server config serving the root.
+    fn config(&self) -> TFTPServerConfig {
+        TFTPServerConfig { root: self.0.join("root"), ..Default::default() }
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for ScratchDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+fn scratch_dir(name: &str) -> ScratchDir {
+    let dir = std::env::temp_dir().join(format!("tftp-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("root/pxelinux.cfg")).unwrap();
+    std::fs::write(dir.join("root/pxelinux.0"), b"loader").unwrap();
+    std::fs::write(dir.join("root/pxelinux.cfg/default"), b"DEFAULT linux").unwrap();
+    std::fs::write(dir.join("secret"), b"shadow").unwrap();
+    ScratchDir(dir)
+}
+
+#[test]
+fn resolve_path_test() {
+    let dir = scratch_dir("resolve");
+    let root = dir.join("root");
+    let denied = |name: &str| resolve_path(&root, name).unwrap_err().kind() == io::ErrorKind::PermissionDenied;
+
+    assert!(resolve_path(&root, "pxelinux.0").is_ok());
+    assert!(resolve_path(&root, "./pxelinux.cfg/default").is_ok());
+    assert!(resolve_path(&root, "pxelinux.cfg\\default").is_ok());
+    assert_eq!(resolve_path(&root, "missing").unwrap_err().kind(), io::ErrorKind::NotFound);
+
+    assert!(denied("../secret"));
+    assert!(denied("pxelinux.cfg/../../secret"));
+    assert!(denied("..\\secret"));
+    assert!(denied("/etc/shadow"));
+    assert!(denied(dir.join("secret").to_str().unwrap()));
+    assert!(denied(""));
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(dir.join("secret"), root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(root.join("pxelinux.0"), root.join("inside")).unwrap();
+        assert!(denied("escape"));
+        assert!(resolve_path(&root, "inside").is_ok());
+    }
+}
+
+#[test]
+fn access_violation_test() {
+    let dir = scratch_dir("violation");
+    let config = dir.config();
+    let files = FsProvider::new(&config.root);
+
+    let err = TFTP::parse_rrq(&TFTP::rrq("../secret", "octet"), &config, &files).err().unwrap();
+    assert_eq!(TFTP::error_from(&err), TFTP::error(2, "Access violation."));
+
+    let err = TFTP::parse_rrq(&TFTP::rrq("missing", "octet"), &config, &files).err().unwrap();
+    assert_eq!(TFTP::error_from(&err), TFTP::error(1, "No such file."));
+}
+
+#[test]
+fn retransmit_test() {
+    let dir = scratch_dir("retransmit");
+    let config = dir.config();
+    let files = FsProvider::new(&config.root);
+    let mut transfer = TFTP::parse_rrq(&TFTP::rrq("pxelinux.0", "octet"), &config, &files).unwrap();
+
+    transfer.options.tsize = Some(transfer.tsize());
+    let oack = vec![TFTP::opt_ack(None, Some(6), None, None)];
+    assert_eq!(transfer.start(), Step::Send(oack.clone()));
+    let start = transfer.sent_at;
+    let timeout = Duration::from_secs(1);
+
+    // Lost OACK is resent until retries run out.
+    assert_eq!(transfer.on_tick(start, timeout, 2), Step::Wait);
+    assert_eq!(transfer.on_tick(start + timeout, timeout, 2), Step::Send(oack.clone()));
+    assert_eq!(transfer.on_tick(start + timeout * 2, timeout, 2), Step::Send(oack));
+    assert_eq!(transfer.on_tick(start + timeout * 3, timeout, 2),
+               Step::Abort(TFTP::error(0, "Transfer timed out.")));
+
+    // Duplicate ACKs don't produce new packets.
+    let data = TFTP::data(1, b"loader".to_vec());
+    assert_eq!(transfer.on_ack(0), Step::Send(vec![data.clone()]));
+    assert_eq!(transfer.on_ack(0), Step::Wait);
+    assert_eq!(transfer.on_tick(Instant::now() + timeout, timeout, 2), Step::Send(vec![data]));
+    assert_eq!(transfer.on_ack(1), Step::Finished);
+}
+
+#[test]
+fn parse_ack_test() {
+    assert_eq!(TFTP::parse_ack(&TFTP::ack(2137)).unwrap(), 2137);
+    assert!(TFTP::parse_ack(&[0x00, 0x04, 0x01]).is_err());
+}
+
+#[test]
+fn transfer_id_test() {
+    let dir = scratch_dir("tid");
+    let config = dir.config();
+    let files = FsProvider::new(&config.root);
+    let transfer = TFTP::parse_rrq(&TFTP::rrq("pxelinux.0", "octet"), &config, &files).unwrap();
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let intruder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    intruder.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+    let session = Session::new(Ipv4Addr::LOCALHOST, client.local_addr().unwrap(), Transfer::Read(transfer)).unwrap();
+    let tid = session.socket.local_addr().unwrap();
+    assert_ne!(tid.port(), 69);
+    let handle = thread::spawn(move || session.run(&config));
+
+    // Intruder's error goes unanswered, its ACK gets one.
+    let mut buf = [0; 512];
+    intruder.send_to(&TFTP::error(0, "TFTP Aborted"), tid).unwrap();
+    intruder.send_to(&TFTP::ack(0), tid).unwrap();
+    let (amt, from) = intruder.recv_from(&mut buf).unwrap();
+    assert_eq!((&buf[..amt], from), (&TFTP::error(5, "Unknown transfer ID.")[..], tid));
+
+    // Intruder didn't disturb the session.
+    client.send_to(&TFTP::ack(0), tid).unwrap();
+    let (amt, from) = client.recv_from(&mut buf).unwrap();
+    assert_eq!((&buf[..amt], from), (&TFTP::data(1, b"loader".to_vec())[..], tid));
+
+    client.send_to(&TFTP::ack(1), tid).unwrap();
+    handle.join().unwrap();
+}
+
+#[cfg(test)]
+fn rrq_with_options(filename: &str, options: &[(&str, &str)]) -> Vec<u8> {
+    options.iter()
+        .fold(TFTP::rrq(filename, "octet"), |mut acc, (key, value)| {
+            acc.extend(str_to_bytes(*key));
+            acc.extend(str_to_bytes(*value));
+            acc
+        })
+}
+
+#[test]
+fn concurrent_transfers_test() {
+    let dir = scratch_dir("concurrent");
+    let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let addr = match addr { SocketAddr::V4(addr) => addr, _ => unreachable!() };
+    let config = TFTPServerConfig { addr, max_transfers: 2, ..dir.config() };
+    thread::spawn(move || TFTPServer::new(config).start());
+    thread::sleep(Duration::from_millis(50));
+
+    let clients = (0..3)
+        .map(|_| {
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            client
+        })
+        .collect::<Vec<UdpSocket>>();
+
+    // Two stalled clients take all the slots, third one is turned away.
+    let mut buf = [0; 512];
+    let mut tids = Vec::new();
+    let rrq = rrq_with_options("pxelinux.0", &[("blksize", "1024")]);
+    for client in &clients[..2] {
+        client.send_to(&rrq, addr).unwrap();
+        let (_, tid) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(buf[1], TFTP::OPT_ACK);
+        tids.push(tid);
+    }
+    clients[2].send_to(&rrq, addr).unwrap();
+    let (amt, _) = clients[2].recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..amt], &TFTP::error(0, "Too many transfers, try again later.")[..]);
+
+    // Stray ACK at the listen port is answered, stray error is not.
+    clients[2].send_to(&TFTP::error(0, "TFTP Aborted"), addr).unwrap();
+    clients[2].send_to(&TFTP::ack(1), addr).unwrap();
+    let (amt, _) = clients[2].recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..amt], &TFTP::error(5, "Unknown transfer ID.")[..]);
+
+    // Second client progresses while first one sits idle.
+    clients[1].send_to(&TFTP::ack(0), tids[1]).unwrap();
+    let (amt, _) = clients[1].recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..amt], &TFTP::data(1, b"loader".to_vec())[..]);
+}
+
+#[test]
+fn negotiation_test() {
+    let dir = scratch_dir("negotiation");
+    let config = TFTPServerConfig { max_blksize: 1468, ..dir.config() };
+    let files = FsProvider::new(&config.root);
+    let start = |rrq: &[u8]| {
+        let mut transfer = TFTP::parse_rrq(rrq, &config, &files).unwrap();
+        (transfer.start(), transfer.block_sz)
+    };
+
+    // No options, no OACK. Plain DATA with 512 byte blocks.
+    assert_eq!(start(&TFTP::rrq("pxelinux.0", "octet")),
+               (Step::Send(vec![TFTP::data(1, b"loader".to_vec())]), 512));
+
+    // Only requested options are acknowledged.
+    assert_eq!(start(&rrq_with_options("pxelinux.0", &[("TSIZE", "0")])),
+               (Step::Send(vec![TFTP::opt_ack(None, Some(6), None, None)]), 512));
+    assert_eq!(start(&rrq_with_options("pxelinux.0", &[("blksize", "1024"), ("foo", "bar")])),
+               (Step::Send(vec![TFTP::opt_ack(Some(1024), None, None, None)]), 1024));
+
+    // Block size is clamped to sane values and configured maximum.
+    assert_eq!(start(&rrq_with_options("pxelinux.0", &[("blksize", "4")])).1, 8);
+    assert_eq!(start(&rrq_with_options("pxelinux.0", &[("blksize", "65000")])).1, 1468);
+}
+
+#[test]
+fn windowsize_test() {
+    let dir = scratch_dir("windowsize");
+    std::fs::write(dir.join("root/initrd"), b"0123456789abcdefghijklmnopqrstuvwxyz").unwrap();
+    let config = TFTPServerConfig { max_windowsize: 4, ..dir.config() };
+    let files = FsProvider::new(&config.root);
+
+    let rrq = rrq_with_options("initrd", &[("blksize", "8"), ("windowsize", "2")]);
+    let mut transfer = TFTP::parse_rrq(&rrq, &config, &files).unwrap();
+    let data = |block: u16, bytes: &[u8]| TFTP::data(block, bytes.to_vec());
+    let timeout = Duration::from_secs(1);
+
+    assert_eq!(transfer.start(), Step::Send(vec![TFTP::opt_ack(Some(8), None, None, Some(2))]));
+    assert_eq!(transfer.on_ack(0), Step::Send(vec![data(1, b"01234567"), data(2, b"89abcdef")]));
+
+    // Block 2 got lost, window slides to block 2 and it goes again.
+    assert_eq!(transfer.on_ack(1), Step::Send(vec![data(2, b"89abcdef"), data(3, b"ghijklmn")]));
+    assert_eq!(transfer.on_ack(1), Step::Wait);
+    assert_eq!(transfer.on_tick(Instant::now() + timeout, timeout, 2),
+               Step::Send(vec![data(2, b"89abcdef"), data(3, b"ghijklmn")]));
+
+    assert_eq!(transfer.on_ack(3), Step::Send(vec![data(4, b"opqrstuv"), data(5, b"wxyz")]));
+    assert_eq!(transfer.on_ack(5), Step::Finished);
+
+    // Window size is clamped to configured maximum, zero means one.
+    let windowsize = |value: &str| {
+        TFTP::parse_rrq(&rrq_with_options("initrd", &[("windowsize", value)]), &config, &files)
+            .unwrap()
+            .window_sz
+    };
+    assert_eq!(windowsize("100"), 4);
+    assert_eq!(windowsize("0"), 1);
+}
+
+#[test]
+fn timeout_option_test() {
+    let dir = scratch_dir("timeout");
+    let config = dir.config();
+    let files = FsProvider::new(&config.root);
+    let parse = |value: &str| TFTP::parse_rrq(&rrq_with_options("pxelinux.0", &[("timeout", value)]), &config, &files).unwrap();
+
+    let mut transfer = parse("2");
+    assert_eq!(transfer.start(), Step::Send(vec![TFTP::opt_ack(None, None, Some(2), None)]));
+    assert_eq!(transfer.options.timeout(config.timeout), Duration::from_secs(2));
+
+    // Negotiated interval drives retransmission, not server default.
+    let start = transfer.sent_at;
+    let timeout = transfer.options.timeout(config.timeout);
+    assert_eq!(transfer.on_tick(start + Duration::from_secs(1), timeout, 5), Step::Wait);
+    assert!(matches!(transfer.on_tick(start + timeout, timeout, 5), Step::Send(_)));
+
+    // Values outside 1-255 seconds are ignored.
+    for value in &["0", "256", "-1", "soon"] {
+        let mut transfer = parse(value);
+        assert_eq!(transfer.options.timeout, None);
+        assert_eq!(transfer.options.timeout(config.timeout), config.timeout);
+        assert_eq!(transfer.start(), Step::Send(vec![TFTP::data(1, b"loader".to_vec())]));
+    }
+}
+
+// Push virtual file through the state machine, ACKing every window of 16 blocks.
+// Returns bytes received and numbers of all DATA packets in order.
+#[cfg(test)]
+fn drain_transfer(block_sz: u16, size: u64, rollover: BlockRollover) -> (u64, Vec<u16>) {
+    let mut transfer = TFTPTransfer::from_reader(block_sz, Box::new(io::repeat(0x55).take(size)), size);
+    transfer.window_sz = 16;
+    transfer.rollover = rollover;
+
+    let (mut received, mut blocks) = (0, Vec::new());
+    let mut step = transfer.start();
+    while let Step::Send(packets) = step {
+        for packet in packets {
+            blocks.push(((packet[2] as u16) << 8) | packet[3] as u16);
+            received += (packet.len() - 4) as u64;
+        }
+        step = transfer.on_ack(*blocks.last().unwrap());
+    }
+    assert_eq!(step, Step::Finished);
+    (received, blocks)
+}
+
+#[test]
+fn rollover_test() {
+    // 70000 blocks of 8 bytes, 8 byte tail means one more, short block.
+    let size = 70000 * 8 + 3;
+    for &(rollover, first) in &[(BlockRollover::Zero, 0), (BlockRollover::One, 1)] {
+        let (received, blocks) = drain_transfer(8, size, rollover);
+        assert_eq!(received, size);
+        assert_eq!(blocks.len(), 70001);
+        assert_eq!(&blocks[65533..65537], &[65534, 65535, first, first + 1]);
+    }
+
+    // File filling the counter exactly ends with empty block 65535.
+    let (received, blocks) = drain_transfer(8, 65534 * 8, BlockRollover::Zero);
+    assert_eq!((received, blocks.len()), (65534 * 8, 65535));
+    assert_eq!(blocks.last(), Some(&65535));
+}
+
+#[test]
+fn large_file_test() {
+    // Sizes past 4 GiB have to survive tsize and byte counting.
+    let size: u64 = (5 << 30) + 123;
+    let mut transfer = TFTPTransfer::from_reader(65464, Box::new(io::repeat(0).take(size)), size);
+    transfer.options.tsize = Some(transfer.tsize());
+    assert_eq!(transfer.start(), Step::Send(vec![TFTP::opt_ack(None, Some(size), None, None)]));
+    assert!(TFTP::opt_ack(None, Some(size), None, None).ends_with(b"tsize\x005368709243\x00"));
+
+    let (received, blocks) = drain_transfer(65464, size, BlockRollover::Zero);
+    assert_eq!(received, size);
+    assert_eq!(blocks.len() as u64, size / 65464 + 1);
+}
+
+#[test]
+fn mode_test() {
+    let dir = scratch_dir("mode");
+    std::fs::write(dir.join("root/menu.cfg"), b"LABEL a\nKERNEL b\r\n").unwrap();
+    let config = dir.config();
+    let files = FsProvider::new(&config.root);
+    let start = |mode: &str| TFTP::parse_rrq(&TFTP::rrq("menu.cfg", mode), &config, &files).map(|mut transfer| transfer.start());
+
+    assert_eq!(start("octet").unwrap(), Step::Send(vec![TFTP::data(1, b"LABEL a\nKERNEL b\r\n".to_vec())]));
+    assert_eq!(start("NetASCII").unwrap(), Step::Send(vec![TFTP::data(1, b"LABEL a\r\nKERNEL b\r\x00\r\n".to_vec())]));
+
+    // Size on disk is not what netascii client gets, so tsize is only answered for octet.
+    let tsize = |mode: &str| {
+        let rrq = [TFTP::rrq("menu.cfg", mode), str_to_bytes("tsize"), str_to_bytes("0")].concat();
+        TFTP::parse_rrq(&rrq, &config, &files).unwrap().start()
+    };
+    assert_eq!(tsize("octet"), Step::Send(vec![TFTP::opt_ack(None, Some(18), None, None)]));
+    assert_eq!(tsize("netascii"), Step::Send(vec![TFTP::data(1, b"LABEL a\r\nKERNEL b\r\x00\r\n".to_vec())]));
+
+    // Mode is checked before the file is even looked up.
+    for mode in &["mail", "binary", ""] {
+        let err = TFTP::parse_rrq(&TFTP::rrq("missing", *mode), &config, &files).err().unwrap();
+        assert_eq!(TFTP::error_from(&err), TFTP::error(4, "Illegal TFTP operation."));
+    }
+}
+
+#[test]
+fn shutdown_test() {
+    let dir = scratch_dir("shutdown");
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let config = dir.config();
+
+    let running = Arc::new(AtomicBool::new(true));
+    let handle = {
+        let running = running.clone();
+        thread::spawn(move || TFTPServer::new(config).serve(socket, &running))
+    };
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut buf = [0; 512];
+    client.send_to(&rrq_with_options("pxelinux.0", &[("tsize", "0")]), addr).unwrap();
+    let (_, tid) = client.recv_from(&mut buf).unwrap();
+
+    // Transfer started before shutdown is completed, later requests are ignored.
+    running.store(false, Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(300));
+    client.send_to(&TFTP::ack(0), tid).unwrap();
+    let (amt, _) = client.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..amt], &TFTP::data(1, b"loader".to_vec())[..]);
+
+    let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+    other.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    other.send_to(&TFTP::rrq("pxelinux.0", "octet"), addr).unwrap();
+    assert!(other.recv_from(&mut buf).is_err());
+
+    assert!(!handle.is_finished());
+    client.send_to(&TFTP::ack(1), tid).unwrap();
+    handle.join().unwrap().unwrap();
+}
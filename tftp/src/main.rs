@@ -1,10 +1,81 @@
-use tftp::TFTPServer;
+use tftp::{BlockRollover, OverwritePolicy, TFTPServer, TFTPServerConfig, MAX_BLKSIZE, MIN_BLKSIZE};
+
+use std::{env, io};
+use std::io::ErrorKind;
 use std::net::SocketAddrV4;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const USAGE: &str = "[--root DIR] [--bind x.x.x.x:pp] [--max-blksize N] [--max-windowsize N] [--timeout SECS] [--retries N] [--max-transfers N] [--rollover 0|1] [--upload-dir DIR] [--overwrite refuse|replace] [--max-upload-size BYTES]";
+
+fn main() -> io::Result<()> {
+    let argv = env::args().collect::<Vec<String>>();
+    let config = parse_args(&argv[1..])
+        .map_err(|msg| {
+            let msg = format!("{}. Usage: {} {}", msg, argv[0], USAGE);
+            io::Error::new(ErrorKind::InvalidInput, msg)
+        })?;
+
+    println!("Serving {:?} on {}...", config.root, config.addr);
+    TFTPServer::new(config)
+        .start()
+}
+
+fn parse_args(args: &[String]) -> Result<TFTPServerConfig, String> {
+    let mut config = TFTPServerConfig::default();
+    let mut args = args.iter();
+
+    while let Some(flag) = args.next() {
+        let value = args.next()
+            .ok_or(format!("Missing value for {}", flag))?;
+        let invalid = || format!("Invalid value for {}: '{}'", flag, value);
+
+        match flag.as_str() {
+            "--root" => config.root = PathBuf::from(value),
+            "--bind" => config.addr = value.parse::<SocketAddrV4>().map_err(|_| invalid())?,
+            // Same limits as RFC 2348 puts on blksize option.
+            "--max-blksize" => config.max_blksize = parse_within(value, MIN_BLKSIZE..=MAX_BLKSIZE).ok_or_else(invalid)?,
+            "--max-windowsize" => config.max_windowsize = parse_within(value, 1..=u16::MAX).ok_or_else(invalid)?,
+            // Same range as timeout option (RFC 2349).
+            "--timeout" => config.timeout = parse_within(value, 1..=255).map(Duration::from_secs).ok_or_else(invalid)?,
+            "--retries" => config.retries = value.parse::<u8>().map_err(|_| invalid())?,
+            "--max-transfers" => config.max_transfers = parse_within(value, 1..=usize::MAX).ok_or_else(invalid)?,
+            "--rollover" => config.block_rollover = match value.as_str() {
+                "0" => BlockRollover::Zero,
+                "1" => BlockRollover::One,
+                _ => return Err(invalid())
+            },
+            "--upload-dir" => config.upload_dir = Some(PathBuf::from(value)),
+            "--overwrite" => config.overwrite = match value.as_str() {
+                "refuse" => OverwritePolicy::Refuse,
+                "replace" => OverwritePolicy::Replace,
+                _ => return Err(invalid())
+            },
+            "--max-upload-size" => config.max_upload_size = value.parse::<u64>().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown flag {}", flag))
+        }
+    }
+
+    Ok(config)
+}
+
+fn parse_within<T: FromStr + PartialOrd>(value: &str, range: RangeInclusive<T>) -> Option<T> {
+    value.parse::<T>().ok()
+        .filter(|value| range.contains(value))
+}
+
+#[test]
+fn parse_args_test() {
+    let parse = |args: &[&str]| parse_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>());
 
-const ADDR: &'static str = "192.168.1.104:69";
+    let config = parse(&["--max-blksize", "1468", "--timeout", "2", "--max-transfers", "1"]).unwrap();
+    assert_eq!((config.max_blksize, config.timeout, config.max_transfers), (1468, Duration::from_secs(2), 1));
 
-fn main() {
-    let addr = ADDR.parse::<SocketAddrV4>().unwrap();
-    TFTPServer::new()
-        .start(addr);
+    // Zero sized blocks or windows, instant timeouts and no transfers at all make no sense.
+    for args in &[["--max-blksize", "7"], ["--max-blksize", "65465"], ["--max-windowsize", "0"],
+                  ["--timeout", "0"], ["--timeout", "256"], ["--max-transfers", "0"]] {
+        assert_eq!(parse(args).err(), Some(format!("Invalid value for {}: '{}'", args[0], args[1])));
+    }
 }
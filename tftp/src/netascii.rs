@@ -0,0 +1,119 @@
+use std::io;
+use std::io::{BufRead, Read};
+
+/*
+ * Netascii (RFC 764) ends lines with CR LF, and bare CR goes as CR NUL.
+ * Both translations work on a stream, so a pair split between two blocks
+ * comes out right.
+ */
+
+// Local file to netascii, for reads.
+pub(crate) struct NetasciiReader<R> {
+    inner: R,
+    // Second byte of a pair which didn't fit into the last buffer.
+    pending: Option<u8>
+}
+
+impl<R: BufRead> NetasciiReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self { inner, pending: None }
+    }
+}
+
+impl<R: BufRead> Read for NetasciiReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = 0;
+        while len < buf.len() {
+            if let Some(byte) = self.pending.take() {
+                buf[len] = byte;
+                len += 1;
+                continue;
+            }
+
+            let byte = match self.inner.fill_buf()?.first() {
+                Some(byte) => *byte,
+                None => break
+            };
+            self.inner.consume(1);
+
+            let (first, second) = match byte {
+                b'\n' => (b'\r', Some(b'\n')),
+                b'\r' => (b'\r', Some(0x00)),
+                byte => (byte, None)
+            };
+            buf[len] = first;
+            len += 1;
+            self.pending = second;
+        }
+        Ok(len)
+    }
+}
+
+// Netascii to local file, for uploads. Fed block by block.
+#[derive(Default)]
+pub(crate) struct NetasciiDecoder {
+    // Last block ended with CR, meaning of which depends on the next byte.
+    cr: bool
+}
+
+impl NetasciiDecoder {
+    pub(crate) fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &byte in data {
+            if self.cr {
+                self.cr = false;
+                match byte {
+                    b'\n' => { out.push(b'\n'); continue; },
+                    0x00 => { out.push(b'\r'); continue; },
+                    // Bare CR from sloppy client, keep it as it is.
+                    _ => out.push(b'\r')
+                }
+            }
+
+            if byte == b'\r' {
+                self.cr = true;
+            } else {
+                out.push(byte);
+            }
+        }
+        out
+    }
+
+    // CR left hanging at the very end of upload.
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        if std::mem::take(&mut self.cr) {
+            return vec![b'\r'];
+        }
+        Vec::new()
+    }
+}
+
+#[test]
+fn netascii_reader_test() {
+    let mut reader = NetasciiReader::new(&b"a\nb\r\n\rc"[..]);
+
+    // Tiny buffers split every pair.
+    let mut out = Vec::new();
+    let mut buf = [0; 3];
+    loop {
+        match reader.read(&mut buf).unwrap() {
+            0 => break,
+            amt => out.push(buf[..amt].to_vec())
+        }
+    }
+    assert_eq!(out.concat(), b"a\r\nb\r\x00\r\n\r\x00c");
+    assert_eq!(out[0], b"a\r\n");
+    assert_eq!(out[1], b"b\r\x00");
+}
+
+#[test]
+fn netascii_decoder_test() {
+    let mut decoder = NetasciiDecoder::default();
+    let blocks: Vec<&[u8]> = vec![b"a\r", b"\nb\r", b"\x00\r\r", b"\n\r"];
+
+    let mut out = blocks.iter()
+        .flat_map(|block| decoder.decode(block))
+        .collect::<Vec<u8>>();
+    out.extend(decoder.finish());
+    assert_eq!(out, b"a\nb\r\r\n\r");
+}
@@ -0,0 +1,144 @@
+use crate::resolve_path;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/*
+ * Source of files served to clients. Filenames come as the client sent
+ * them, so implementation is responsible for keeping clients where they
+ * belong. Shared by all transfer threads.
+ */
+pub trait FileProvider: Send + Sync {
+    // Reader at the start of the file and file size in bytes.
+    fn open(&self, filename: &str) -> io::Result<(Box<dyn Read + Send>, u64)>;
+}
+
+// Files under a directory on disk.
+pub struct FsProvider {
+    root: PathBuf
+}
+
+impl FsProvider {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl FileProvider for FsProvider {
+    fn open(&self, filename: &str) -> io::Result<(Box<dyn Read + Send>, u64)> {
+        let path = resolve_path(&self.root, filename)?;
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Ok((Box::new(file), size))
+    }
+}
+
+// Fixed set of files kept in memory. Both '/' and '\\' work as separators.
+#[derive(Clone, Default)]
+pub struct MemoryProvider {
+    files: HashMap<String, Arc<[u8]>>
+}
+
+impl MemoryProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(mut self, filename: impl Into<String>, content: impl Into<Vec<u8>>) -> Self {
+        self.files.insert(filename.into().replace('\\', "/"), content.into().into());
+        self
+    }
+}
+
+impl FileProvider for MemoryProvider {
+    fn open(&self, filename: &str) -> io::Result<(Box<dyn Read + Send>, u64)> {
+        let content = self.files.get(&filename.replace('\\', "/"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such file."))?;
+        Ok((Box::new(Cursor::new(content.clone())), content.len() as u64))
+    }
+}
+
+// Content made up on request by a callback. 'None' means there's no such file.
+pub struct GeneratedProvider<F> {
+    generate: F
+}
+
+impl<F> GeneratedProvider<F>
+where F: Fn(&str) -> Option<Vec<u8>> + Send + Sync {
+    pub fn new(generate: F) -> Self {
+        Self { generate }
+    }
+}
+
+impl<F> FileProvider for GeneratedProvider<F>
+where F: Fn(&str) -> Option<Vec<u8>> + Send + Sync {
+    fn open(&self, filename: &str) -> io::Result<(Box<dyn Read + Send>, u64)> {
+        let content = (self.generate)(filename)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such file."))?;
+        let size = content.len() as u64;
+        Ok((Box::new(Cursor::new(content)), size))
+    }
+}
+
+#[test]
+fn provider_test() {
+    use crate::{Step, TFTPServerConfig, TFTP};
+
+    let config = TFTPServerConfig::default();
+    let start = |files: &dyn FileProvider, filename: &str| {
+        TFTP::parse_rrq(&TFTP::rrq(filename, "octet"), &config, files)
+            .map(|mut transfer| transfer.start())
+            .map_err(|err| TFTP::error_from(&err))
+    };
+    let data = |bytes: &[u8]| Ok(Step::Send(vec![TFTP::data(1, bytes.to_vec())]));
+    let missing = Err(TFTP::error(1, "No such file."));
+
+    let memory = MemoryProvider::new()
+        .insert("pxelinux.0", b"loader".to_vec())
+        .insert("pxelinux.cfg/default", "DEFAULT linux");
+    assert_eq!(start(&memory, "pxelinux.0"), data(b"loader"));
+    assert_eq!(start(&memory, "pxelinux.cfg\\default"), data(b"DEFAULT linux"));
+    assert_eq!(start(&memory, "../pxelinux.0"), missing);
+
+    let generated = GeneratedProvider::new(|filename: &str| {
+        filename.strip_prefix("hello/").map(|name| format!("Hello {}!", name).into_bytes())
+    });
+    assert_eq!(start(&generated, "hello/world"), data(b"Hello world!"));
+    assert_eq!(start(&generated, "pxelinux.0"), missing);
+}
+
+#[test]
+fn memory_server_test() {
+    use crate::{TFTPServer, TFTPServerConfig, TFTP};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    let addr = match UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        _ => unreachable!()
+    };
+    // Root doesn't exist, everything comes from memory.
+    let config = TFTPServerConfig { root: PathBuf::from("/nonexistent"), addr, ..Default::default() };
+    let files = MemoryProvider::new().insert("boot.ipxe", "#!ipxe\nchain http://10.0.0.1/menu\n");
+    std::thread::spawn(move || TFTPServer::new(config).files(files).start());
+    std::thread::sleep(Duration::from_millis(50));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut buf = [0; 512];
+
+    client.send_to(&TFTP::rrq("boot.ipxe", "octet"), addr).unwrap();
+    let (amt, tid) = client.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..amt], &TFTP::data(1, b"#!ipxe\nchain http://10.0.0.1/menu\n".to_vec())[..]);
+    client.send_to(&TFTP::ack(1), tid).unwrap();
+
+    let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+    other.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    other.send_to(&TFTP::rrq("pxelinux.0", "octet"), addr).unwrap();
+    let (amt, _) = other.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..amt], &TFTP::error(1, "No such file.")[..]);
+}
@@ -0,0 +1,251 @@
+use crate::FileProvider;
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Cursor, Read};
+use std::net::Ipv4Addr;
+
+const CONFIG_DIR: &str = "pxelinux.cfg/";
+
+// Used when no template is given. Variables go in double braces.
+pub const DEFAULT_TEMPLATE: &str = "DEFAULT {{hostname}}\n\
+                                    LABEL {{hostname}}\n  \
+                                    KERNEL {{kernel}}\n  \
+                                    APPEND {{append}}\n";
+
+// Machine with config of its own, and values filled into the template for it.
+#[derive(Clone, Debug)]
+pub struct PxeHost {
+    mac: [u8; 6],
+    uuid: Option<[u8; 16]>,
+    ip: Option<Ipv4Addr>,
+    vars: HashMap<String, String>
+}
+
+impl PxeHost {
+    pub fn new(mac: [u8; 6]) -> Self {
+        let mut vars = HashMap::new();
+        vars.insert("mac".to_string(), hex_mac(&mac));
+        Self { mac, uuid: None, ip: None, vars }
+    }
+
+    // Also answer to the UUID file, client sends it in DHCP option 97.
+    pub fn uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.vars.insert("uuid".to_string(), hex_uuid(&uuid));
+        self.uuid = Some(uuid);
+        self
+    }
+
+    // Also answer to the hex IP file of this address.
+    pub fn ip(mut self, ip: Ipv4Addr) -> Self {
+        self.vars.insert("ip".to_string(), ip.to_string());
+        self.ip = Some(ip);
+        self
+    }
+
+    pub fn var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.vars.insert(name.into(), value.into());
+        self
+    }
+}
+
+/*
+ * PXELINUX looks for its config under 'pxelinux.cfg/' trying its UUID,
+ * then '01-<mac>', then its IP in hex with one digit less each time, then
+ * 'default'. UUID, MAC and full IP files of known hosts, and 'default'
+ * when one is configured, are rendered from templates. Everything else,
+ * hand-written configs included, comes from the wrapped provider, so the
+ * search goes on there just like it would on disk.
+ */
+pub struct PxelinuxProvider {
+    files: Box<dyn FileProvider>,
+    template: String,
+    default: Option<String>,
+    hosts: Vec<PxeHost>
+}
+
+impl PxelinuxProvider {
+    pub fn new(files: impl FileProvider + 'static) -> Self {
+        Self {
+            files: Box::new(files),
+            template: DEFAULT_TEMPLATE.to_string(),
+            default: None,
+            hosts: Vec::new()
+        }
+    }
+
+    pub fn template(mut self, template: impl Into<String>) -> Self {
+        self.template = template.into();
+        self
+    }
+
+    // Served as 'pxelinux.cfg/default', no host variables available.
+    pub fn default_config(mut self, config: impl Into<String>) -> Self {
+        self.default = Some(config.into());
+        self
+    }
+
+    pub fn host(mut self, host: PxeHost) -> Self {
+        self.hosts.push(host);
+        self
+    }
+
+    // Config text for the file, if it's one of ours.
+    fn generate(&self, filename: &str) -> Option<String> {
+        let name = filename.replace('\\', "/");
+        let name = name.strip_prefix(CONFIG_DIR)?;
+
+        if name == "default" {
+            return self.default.clone();
+        }
+
+        let host = if let Some(uuid) = parse_uuid_file(name) {
+            self.hosts.iter().find(|host| host.uuid == Some(uuid))
+        } else if let Some(mac) = parse_mac_file(name) {
+            self.hosts.iter().find(|host| host.mac == mac)
+        } else {
+            parse_ip_file(name).and_then(|ip| self.hosts.iter().find(|host| host.ip == Some(ip)))
+        }?;
+        Some(render(&self.template, &host.vars))
+    }
+}
+
+impl FileProvider for PxelinuxProvider {
+    fn open(&self, filename: &str) -> io::Result<(Box<dyn Read + Send>, u64)> {
+        match self.generate(filename) {
+            Some(config) => {
+                let config = config.into_bytes();
+                let size = config.len() as u64;
+                Ok((Box::new(Cursor::new(config)), size))
+            },
+            None => self.files.open(filename)
+        }
+    }
+}
+
+// 'xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx', bytes in the order option 97 carries them.
+fn parse_uuid_file(name: &str) -> Option<[u8; 16]> {
+    let groups = name.split('-').map(str::len).collect::<Vec<usize>>();
+    if groups != [8, 4, 4, 4, 12] || !name.is_ascii() {
+        return None;
+    }
+    let digits = name.replace('-', "");
+    let mut uuid = [0; 16];
+    for (idx, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[idx * 2..idx * 2 + 2], 16).ok()?;
+    }
+    Some(uuid)
+}
+
+// '01-aa-bb-cc-dd-ee-ff', ARP type 1 is Ethernet.
+fn parse_mac_file(name: &str) -> Option<[u8; 6]> {
+    let mut parts = name.strip_prefix("01-")?.split('-');
+    let mut mac = [0; 6];
+    for byte in mac.iter_mut() {
+        let part = parts.next().filter(|part| part.len() == 2)?;
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().map_or(Some(mac), |_| None)
+}
+
+// Only full address, shorter prefixes are left to hand-written configs.
+fn parse_ip_file(name: &str) -> Option<Ipv4Addr> {
+    if name.len() != 8 {
+        return None;
+    }
+    u32::from_str_radix(name, 16).ok().map(Ipv4Addr::from)
+}
+
+fn hex_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+fn hex_uuid(uuid: &[u8; 16]) -> String {
+    let hex = uuid.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+// Replace '{{name}}' with variable value. Unknown variables come out empty.
+fn render(template: &str, vars: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break
+        };
+        out.push_str(&rest[..start]);
+        let name = rest[start + 2..end].trim();
+        out.push_str(vars.get(name).map(String::as_str).unwrap_or(""));
+        rest = &rest[end + 2..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+#[test]
+fn render_test() {
+    let host = PxeHost::new([0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD])
+        .var("hostname", "node1");
+
+    assert_eq!(render("{{hostname}} {{ mac }} [{{missing}}] {{open", &host.vars),
+               "node1 88-99-aa-bb-cc-dd [] {{open");
+    assert_eq!(parse_mac_file("01-88-99-AA-bb-cc-dd"), Some(host.mac));
+    assert_eq!(parse_mac_file("01-88-99-aa-bb-cc"), None);
+    assert_eq!(parse_mac_file("01-88-99-aa-bb-cc-dd-ee"), None);
+    assert_eq!(parse_ip_file("C0A8010A"), Some(Ipv4Addr::new(192, 168, 1, 10)));
+
+    let uuid = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF];
+    assert_eq!(hex_uuid(&uuid), "00112233-4455-6677-8899-aabbccddeeff");
+    assert_eq!(parse_uuid_file("00112233-4455-6677-8899-AABBCCDDEEFF"), Some(uuid));
+    assert_eq!(parse_uuid_file("0011223344556677-8899-aabb-ccddeeff"), None);
+    assert_eq!(parse_uuid_file("00112233-4455-6677-8899-aabbccddeefg"), None);
+    assert_eq!(parse_ip_file("C0A801"), None);
+}
+
+#[test]
+fn pxelinux_provider_test() {
+    use crate::MemoryProvider;
+
+    let files = MemoryProvider::new()
+        .insert("pxelinux.cfg/C0A801", "DEFAULT subnet")
+        .insert("pxelinux.cfg/default", "DEFAULT disk");
+    let provider = PxelinuxProvider::new(files)
+        .host(PxeHost::new([0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD])
+              .uuid([0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF])
+              .ip(Ipv4Addr::new(192, 168, 1, 10))
+              .var("hostname", "node1")
+              .var("kernel", "vmlinuz")
+              .var("append", "initrd=initrd.img console=ttyS0"));
+    let read = |provider: &PxelinuxProvider, filename: &str| {
+        provider.open(filename).ok().map(|(mut reader, size)| {
+            let mut text = String::new();
+            reader.read_to_string(&mut text).unwrap();
+            assert_eq!(text.len() as u64, size);
+            text
+        })
+    };
+
+    let node1 = "DEFAULT node1\nLABEL node1\n  KERNEL vmlinuz\n  APPEND initrd=initrd.img console=ttyS0\n";
+    assert_eq!(read(&provider, "pxelinux.cfg/00112233-4455-6677-8899-aabbccddeeff").as_deref(), Some(node1));
+    assert_eq!(read(&provider, "pxelinux.cfg/01-88-99-aa-bb-cc-dd").as_deref(), Some(node1));
+    assert_eq!(read(&provider, "pxelinux.cfg/C0A8010A").as_deref(), Some(node1));
+
+    // Unknown machine walks the same path as PXELINUX would on disk.
+    assert_eq!(read(&provider, "pxelinux.cfg/ffeeddcc-bbaa-9988-7766-554433221100"), None);
+    assert_eq!(read(&provider, "pxelinux.cfg/01-00-11-22-33-44-55"), None);
+    assert_eq!(read(&provider, "pxelinux.cfg/C0A8010B"), None);
+    assert_eq!(read(&provider, "pxelinux.cfg/C0A801").as_deref(), Some("DEFAULT subnet"));
+    assert_eq!(read(&provider, "pxelinux.cfg/default").as_deref(), Some("DEFAULT disk"));
+
+    let provider = provider
+        .template("LABEL {{hostname}} ({{ip}} {{uuid}})\n")
+        .default_config("DEFAULT menu");
+    assert_eq!(read(&provider, "pxelinux.cfg\\01-88-99-aa-bb-cc-dd").as_deref(), Some("LABEL node1 (192.168.1.10 00112233-4455-6677-8899-aabbccddeeff)\n"));
+    assert_eq!(read(&provider, "pxelinux.cfg/default").as_deref(), Some("DEFAULT menu"));
+}
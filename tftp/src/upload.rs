@@ -0,0 +1,363 @@
+use crate::{access_denied, relative_path, BlockRollover, Mode, Step, TFTPOptions, TFTPServerConfig, TFTP, DEFAULT_BLKSIZE};
+use crate::netascii::NetasciiDecoder;
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// Tells apart partial files of concurrent uploads with the same name. Process
+// id keeps them apart from ones left behind by earlier runs.
+static PART_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverwritePolicy {
+    // Existing file wins, client gets 'File already exists.'
+    Refuse,
+    // Uploaded file replaces existing one once complete.
+    Replace
+}
+
+/*
+ * File received from the client (WRQ). Blocks go into a hidden partial file
+ * next to the target, which is linked or renamed into place only after the
+ * last block arrived. Unfinished upload removes its partial file when dropped.
+ */
+pub(crate) struct TFTPUpload {
+    pub(crate) options: TFTPOptions,
+    // Last block received and acknowledged.
+    block_cnt: u16,
+    block_sz: u16,
+    rollover: BlockRollover,
+    overwrite: OverwritePolicy,
+    path: PathBuf,
+    part: PathBuf,
+    file: File,
+    // Set for netascii uploads.
+    decoder: Option<NetasciiDecoder>,
+    size: u64,
+    max_size: u64,
+    done: bool,
+    // Last ACK or OACK, kept for retransmission.
+    last: Vec<u8>,
+    pub(crate) sent_at: Instant,
+    retries: u8
+}
+
+impl TFTPUpload {
+    pub(crate) fn new(dir: &Path, filename: &str, mode: Mode, options: TFTPOptions, config: &TFTPServerConfig) -> io::Result<Self> {
+        let path = resolve_upload_path(dir, filename)?;
+
+        if config.overwrite == OverwritePolicy::Refuse && path.symlink_metadata().is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "File already exists."));
+        }
+        if options.tsize.is_some_and(|size| size > config.max_upload_size) {
+            return Err(io::Error::new(io::ErrorKind::FileTooLarge, "Upload too large."));
+        }
+
+        let name = path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let part = path.with_file_name(format!(".{}.{}.{}.part", name, std::process::id(), PART_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let file = OpenOptions::new().write(true).create_new(true).open(&part)?;
+
+        Ok(TFTPUpload {
+            block_cnt: 0,
+            block_sz: options.blksize.unwrap_or(DEFAULT_BLKSIZE),
+            rollover: config.block_rollover,
+            overwrite: config.overwrite,
+            path,
+            part,
+            file,
+            decoder: if mode == Mode::Netascii { Some(NetasciiDecoder::default()) } else { None },
+            size: 0,
+            max_size: config.max_upload_size,
+            done: false,
+            last: Vec::new(),
+            sent_at: Instant::now(),
+            retries: 0,
+            options
+        })
+    }
+
+    // OACK if client asked for any option, otherwise ACK 0. Either one invites DATA block 1.
+    pub(crate) fn start(&mut self) -> Step {
+        self.last = if self.options == TFTPOptions::default() {
+            TFTP::ack(0)
+        } else {
+            TFTP::opt_ack(self.options.blksize, self.options.tsize, self.options.timeout, None)
+        };
+        self.sent();
+        Step::Send(vec![self.last.clone()])
+    }
+
+    fn sent(&mut self) {
+        self.sent_at = Instant::now();
+        self.retries = 0;
+    }
+
+    pub(crate) fn on_data(&mut self, block: u16, data: &[u8]) -> Step {
+        // Our ACK got lost and client sent the block again.
+        if block == self.block_cnt {
+            self.sent();
+            return Step::Send(vec![self.last.clone()]);
+        }
+        if self.done || block != self.rollover.next(self.block_cnt) {
+            return Step::Wait;
+        }
+        if data.len() > self.block_sz as usize {
+            return Step::Abort(TFTP::error(4, "Illegal TFTP operation."));
+        }
+
+        self.size += data.len() as u64;
+        if self.size > self.max_size {
+            return Step::Abort(TFTP::error(3, "Disk full or allocation exceeded."));
+        }
+        let last = data.len() < self.block_sz as usize;
+        let data = match &mut self.decoder {
+            Some(decoder) if last => [decoder.decode(data), decoder.finish()].concat(),
+            Some(decoder) => decoder.decode(data),
+            None => data.to_vec()
+        };
+        if let Err(err) = self.file.write_all(&data) {
+            return Step::Abort(TFTP::error_from(&err));
+        }
+
+        // Short block ends the upload.
+        self.block_cnt = block;
+        if last {
+            if let Err(err) = self.commit() {
+                println!("{}", err);
+                return Step::Abort(TFTP::error_from(&err));
+            }
+            self.done = true;
+        }
+
+        self.last = TFTP::ack(block);
+        self.sent();
+        Step::Send(vec![self.last.clone()])
+    }
+
+    pub(crate) fn on_tick(&mut self, now: Instant, timeout: Duration, max_retries: u8) -> Step {
+        if now.duration_since(self.sent_at) < timeout {
+            return Step::Wait;
+        }
+        // Final ACK wasn't answered with retransmitted block, so client got it.
+        if self.done {
+            return Step::Finished;
+        }
+        if self.retries >= max_retries {
+            return Step::Abort(TFTP::error(0, "Transfer timed out."));
+        }
+
+        self.retries += 1;
+        self.sent_at = now;
+        Step::Send(vec![self.last.clone()])
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        match self.overwrite {
+            // Link fails if file showed up meanwhile, so it's never clobbered.
+            OverwritePolicy::Refuse => {
+                fs::hard_link(&self.part, &self.path)?;
+                fs::remove_file(&self.part)
+            },
+            OverwritePolicy::Replace => fs::rename(&self.part, &self.path)
+        }
+    }
+}
+
+impl Drop for TFTPUpload {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = fs::remove_file(&self.part);
+        }
+    }
+}
+
+/*
+ * Partial files of uploads interrupted by a crash or kill. Nothing will ever
+ * finish them, so they are removed from the whole upload tree on startup.
+ * Only names we give partial files are touched, and only once the process
+ * which wrote them is gone.
+ */
+pub(crate) fn remove_stale_parts(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if file_type.is_dir() {
+            remove_stale_parts(&entry.path())?;
+        } else if file_type.is_file() && part_owner(&name).is_some_and(|pid| !process_alive(pid)) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+// Process id out of '.<name>.<pid>.<counter>.part'.
+fn part_owner(name: &str) -> Option<u32> {
+    let mut parts = name.strip_prefix('.')?.strip_suffix(".part")?.rsplitn(3, '.');
+    parts.next()?.parse::<usize>().ok()?;
+    let pid = parts.next()?.parse::<u32>().ok()?;
+    parts.next().filter(|name| !name.is_empty())?;
+    Some(pid)
+}
+
+// Without procfs there's no telling, so every process counts as alive.
+fn process_alive(pid: u32) -> bool {
+    let proc = Path::new("/proc");
+    !proc.join("self").exists() || proc.join(pid.to_string()).exists()
+}
+
+/*
+ * Target of an upload inside the directory. Same rules as for reads, except
+ * the file itself doesn't have to exist yet. Its directory does.
+ */
+fn resolve_upload_path(dir: &Path, filename: &str) -> io::Result<PathBuf> {
+    let relative = relative_path(filename)?;
+    let name = relative.file_name().ok_or_else(access_denied)?;
+
+    let dir = dir.canonicalize()?;
+    let parent = dir.join(&relative)
+        .parent()
+        .unwrap_or(&dir)
+        .canonicalize()?;
+    if !parent.starts_with(&dir) {
+        return Err(access_denied());
+    }
+
+    Ok(parent.join(name))
+}
+
+#[cfg(test)]
+fn upload_config(name: &str) -> (crate::ScratchDir, TFTPServerConfig) {
+    let dir = crate::scratch_dir(name);
+    fs::create_dir_all(dir.join("upload/logs")).unwrap();
+    let config = TFTPServerConfig {
+        upload_dir: Some(dir.join("upload")),
+        max_upload_size: 20,
+        ..dir.config()
+    };
+    (dir, config)
+}
+
+#[cfg(test)]
+fn wrq_with_options(filename: &str, options: &[(&str, &str)]) -> Vec<u8> {
+    let mut wrq = crate::rrq_with_options(filename, options);
+    wrq[1] = TFTP::WRQ;
+    wrq
+}
+
+#[test]
+fn upload_test() {
+    let (dir, config) = upload_config("upload");
+    let upload_dir = dir.join("upload/logs");
+    let entries = || fs::read_dir(&upload_dir).unwrap().count();
+
+    let mut upload = TFTP::parse_wrq(&wrq_with_options("logs/dmesg", &[("blksize", "8")]), &config).unwrap();
+    assert_eq!(upload.start(), Step::Send(vec![TFTP::opt_ack(Some(8), None, None, None)]));
+    assert_eq!(upload.on_data(1, b"01234567"), Step::Send(vec![TFTP::ack(1)]));
+
+    // Duplicate block is ACKed again, block from the future is ignored.
+    assert_eq!(upload.on_data(1, b"01234567"), Step::Send(vec![TFTP::ack(1)]));
+    assert_eq!(upload.on_data(3, b"89"), Step::Wait);
+
+    // Nothing visible under the real name until the last block.
+    assert_eq!(entries(), 1);
+    assert!(!upload_dir.join("dmesg").exists());
+
+    assert_eq!(upload.on_data(2, b"89"), Step::Send(vec![TFTP::ack(2)]));
+    assert_eq!(fs::read(upload_dir.join("dmesg")).unwrap(), b"0123456789");
+    assert_eq!(upload.on_data(2, b"89"), Step::Send(vec![TFTP::ack(2)]));
+
+    let timeout = Duration::from_secs(1);
+    assert_eq!(upload.on_tick(Instant::now() + timeout, timeout, 5), Step::Finished);
+    drop(upload);
+    assert_eq!(entries(), 1);
+
+    // Netascii line endings are translated, CR NUL split between blocks too.
+    let wrq = [TFTP::wrq("logs/boot.txt", "netascii"), crate::str_to_bytes("blksize"), crate::str_to_bytes("8")].concat();
+    let mut upload = TFTP::parse_wrq(&wrq, &config).unwrap();
+    upload.start();
+    assert_eq!(upload.on_data(1, b"line\r\nx\r"), Step::Send(vec![TFTP::ack(1)]));
+    assert_eq!(upload.on_data(2, b"\x00"), Step::Send(vec![TFTP::ack(2)]));
+    assert_eq!(fs::read(upload_dir.join("boot.txt")).unwrap(), b"line\nx\r");
+}
+
+#[test]
+fn upload_policy_test() {
+    let (dir, mut config) = upload_config("upload-policy");
+    let error = |wrq: &[u8], config: &TFTPServerConfig| TFTP::error_from(&TFTP::parse_wrq(wrq, config).err().unwrap());
+    fs::write(dir.join("upload/fw.bin"), b"old").unwrap();
+
+    assert_eq!(error(&TFTP::wrq("../escape", "octet"), &config), TFTP::error(2, "Access violation."));
+    assert_eq!(error(&TFTP::wrq("missing/dir/file", "octet"), &config), TFTP::error(1, "No such file."));
+    assert_eq!(error(&TFTP::wrq("fw.bin", "octet"), &config), TFTP::error(6, "File already exists."));
+    assert_eq!(error(&wrq_with_options("big.bin", &[("tsize", "21")]), &config),
+               TFTP::error(3, "Disk full or allocation exceeded."));
+
+    // Upload growing past the limit is aborted and leaves nothing behind.
+    let mut upload = TFTP::parse_wrq(&wrq_with_options("big.bin", &[("blksize", "16")]), &config).unwrap();
+    upload.start();
+    assert_eq!(upload.on_data(1, &[0; 16]), Step::Send(vec![TFTP::ack(1)]));
+    assert_eq!(upload.on_data(2, &[0; 8]), Step::Abort(TFTP::error(3, "Disk full or allocation exceeded.")));
+    drop(upload);
+    assert_eq!(fs::read_dir(dir.join("upload")).unwrap().count(), 2);
+
+    // File created by someone else during the upload is left alone.
+    let mut upload = TFTP::parse_wrq(&TFTP::wrq("race.bin", "octet"), &config).unwrap();
+    upload.start();
+    fs::write(dir.join("upload/race.bin"), b"theirs").unwrap();
+    assert_eq!(upload.on_data(1, b"ours"), Step::Abort(TFTP::error(6, "File already exists.")));
+    drop(upload);
+    assert_eq!(fs::read(dir.join("upload/race.bin")).unwrap(), b"theirs");
+    assert_eq!(fs::read_dir(dir.join("upload")).unwrap().count(), 3);
+
+    config.overwrite = OverwritePolicy::Replace;
+    let mut upload = TFTP::parse_wrq(&TFTP::wrq("fw.bin", "octet"), &config).unwrap();
+    assert_eq!(upload.start(), Step::Send(vec![TFTP::ack(0)]));
+    assert_eq!(upload.on_data(1, b"new"), Step::Send(vec![TFTP::ack(1)]));
+    assert_eq!(fs::read(dir.join("upload/fw.bin")).unwrap(), b"new");
+
+    // Without blksize option blocks are 512 bytes, short one ends the upload.
+    config.max_upload_size = 1024;
+    let mut upload = TFTP::parse_wrq(&TFTP::wrq("dump.bin", "octet"), &config).unwrap();
+    assert_eq!(upload.start(), Step::Send(vec![TFTP::ack(0)]));
+    assert_eq!(upload.on_data(1, &[1; 512]), Step::Send(vec![TFTP::ack(1)]));
+    assert!(!dir.join("upload/dump.bin").exists());
+    assert_eq!(upload.on_data(2, &[2; 100]), Step::Send(vec![TFTP::ack(2)]));
+    assert_eq!(fs::read(dir.join("upload/dump.bin")).unwrap().len(), 612);
+
+    config.upload_dir = None;
+    assert_eq!(error(&TFTP::wrq("fw.bin", "octet"), &config), TFTP::error(2, "Access violation."));
+}
+
+#[test]
+fn stale_part_test() {
+    let (dir, config) = upload_config("upload-stale");
+    let own = format!(".fw.bin.{}.0.part", std::process::id());
+    fs::write(dir.join("upload/.fw.bin.999999999.0.part"), b"old").unwrap();
+    fs::write(dir.join("upload/logs/.dmesg.999999999.3.part"), b"old").unwrap();
+    fs::write(dir.join("upload").join(&own), b"in flight").unwrap();
+
+    // Client's upload named like a partial file.
+    let mut upload = TFTP::parse_wrq(&TFTP::wrq(".notes.part", "octet"), &config).unwrap();
+    upload.start();
+    assert_eq!(upload.on_data(1, b"done"), Step::Send(vec![TFTP::ack(1)]));
+    drop(upload);
+    fs::write(dir.join("upload/..1.2.part"), b"done").unwrap();
+
+    // Leftovers of dead process go, partial file of live one and finished
+    // uploads which only look like partial files stay.
+    remove_stale_parts(&dir.join("upload")).unwrap();
+    assert!(!dir.join("upload/.fw.bin.999999999.0.part").exists());
+    assert!(!dir.join("upload/logs/.dmesg.999999999.3.part").exists());
+    assert!(dir.join("upload").join(&own).exists());
+    assert_eq!(fs::read(dir.join("upload/.notes.part")).unwrap(), b"done");
+    assert_eq!(fs::read(dir.join("upload/..1.2.part")).unwrap(), b"done");
+}